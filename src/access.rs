@@ -0,0 +1,353 @@
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::AppResponse::Forbidden;
+use crate::cli::{Cli, ForwardedHeader};
+use crate::tls::ConnectionInfo;
+
+/// Network in CIDR notation like `10.0.0.0/8` or a single address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+        let addr = IpAddr::from_str(addr)
+            .map_err(|_| format!("invalid network '{s}', expected e.g. '10.0.0.0/8'"))?
+            .to_canonical();
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length in network '{s}'"))?,
+            None => max_prefix_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(ip))
+}
+
+/// IP address of the sending system, taken from forwarding headers of trusted proxies
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl Display for ClientIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Returns the addresses of the given forwarding header ordered from client to last proxy.
+/// Unparsable entries like `unknown` are `None`.
+fn forwarded_for(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let name = match header {
+        ForwardedHeader::XForwardedFor => "x-forwarded-for",
+        ForwardedHeader::Forwarded => "forwarded",
+    };
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+
+    match header {
+        ForwardedHeader::XForwardedFor => values.map(parse_node).collect(),
+        ForwardedHeader::Forwarded => values
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value.trim_matches('"')))
+            })
+            .collect(),
+    }
+}
+
+/// Parses a node like `192.0.2.1`, `192.0.2.1:4711`, `2001:db8::1` or `[2001:db8::1]:4711`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    IpAddr::from_str(node)
+        .or_else(|_| SocketAddr::from_str(node).map(|addr| addr.ip()))
+        .or_else(|_| IpAddr::from_str(node.trim_start_matches('[').trim_end_matches(']')))
+        .ok()
+}
+
+/// Network access rules applied to all requests of sending systems
+pub struct AccessControl {
+    allowed_networks: RwLock<Vec<IpNet>>,
+    trusted_proxies: RwLock<Vec<IpNet>>,
+    forwarded_header: RwLock<ForwardedHeader>,
+}
+
+impl AccessControl {
+    pub fn new(
+        allowed_networks: Vec<IpNet>,
+        trusted_proxies: Vec<IpNet>,
+        forwarded_header: ForwardedHeader,
+    ) -> Self {
+        Self {
+            allowed_networks: RwLock::new(allowed_networks),
+            trusted_proxies: RwLock::new(trusted_proxies),
+            forwarded_header: RwLock::new(forwarded_header),
+        }
+    }
+
+    pub fn from_config(cli: &Cli) -> Self {
+        Self::new(
+            cli.allowed_networks.clone(),
+            cli.trusted_proxies.clone(),
+            cli.forwarded_header,
+        )
+    }
+
+    /// Replaces allowed networks, trusted proxies and forwarding header after the
+    /// configuration has been reloaded
+    pub fn reload_config(&self, cli: &Cli) {
+        if let Ok(mut allowed_networks) = self.allowed_networks.write() {
+            allowed_networks.clone_from(&cli.allowed_networks);
+        }
+        if let Ok(mut trusted_proxies) = self.trusted_proxies.write() {
+            trusted_proxies.clone_from(&cli.trusted_proxies);
+        }
+        if let Ok(mut forwarded_header) = self.forwarded_header.write() {
+            *forwarded_header = cli.forwarded_header;
+        }
+    }
+
+    /// Returns the client address. The configured forwarding header is used only if sent by a
+    /// trusted proxy, the rightmost address not belonging to a trusted proxy is the client.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        let (Ok(trusted_proxies), Ok(forwarded_header)) =
+            (self.trusted_proxies.read(), self.forwarded_header.read())
+        else {
+            return client;
+        };
+        if !contains(&trusted_proxies, client) {
+            return client;
+        }
+        for node in forwarded_for(headers, *forwarded_header).into_iter().rev() {
+            let Some(node) = node else {
+                break;
+            };
+            client = node.to_canonical();
+            if !contains(&trusted_proxies, client) {
+                break;
+            }
+        }
+        client
+    }
+
+    pub fn is_allowed(&self, client_ip: Option<IpAddr>) -> bool {
+        self.allowed_networks
+            .read()
+            .is_ok_and(|allowed_networks| is_allowed(&allowed_networks, client_ip))
+    }
+}
+
+/// Returns true if no networks are configured or the address is contained in one of them
+pub fn is_allowed(networks: &[IpNet], client_ip: Option<IpAddr>) -> bool {
+    networks.is_empty() || client_ip.is_some_and(|client_ip| contains(networks, client_ip))
+}
+
+/// Determines the client address and rejects requests from networks not allowed
+pub async fn check_network(
+    State(access): State<Arc<AccessControl>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<ConnectionInfo>>()
+        .map(|ConnectInfo(connection_info)| {
+            access.client_ip(connection_info.remote_addr.ip(), request.headers())
+        });
+
+    if !access.is_allowed(client_ip) {
+        log::warn!(
+            "Request from '{}' rejected, network not allowed",
+            client_ip.map(|ip| ip.to_string()).unwrap_or_default()
+        );
+        return Forbidden.into_response();
+    }
+
+    if let Some(client_ip) = client_ip {
+        request.extensions_mut().insert(ClientIp(client_ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access::{AccessControl, IpNet, is_allowed};
+    use crate::cli::ForwardedHeader;
+    use axum::http::{HeaderMap, HeaderValue};
+    use rstest::rstest;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn ip(ip: &str) -> IpAddr {
+        IpAddr::from_str(ip).unwrap_or(IpAddr::from([0, 0, 0, 0]))
+    }
+
+    fn networks(networks: &[&str]) -> Vec<IpNet> {
+        networks
+            .iter()
+            .filter_map(|network| IpNet::from_str(network).ok())
+            .collect()
+    }
+
+    #[rstest]
+    #[case("10.0.0.0/8", "10.1.2.3", true)]
+    #[case("10.0.0.0/8", "11.1.2.3", false)]
+    #[case("192.168.1.10", "192.168.1.10", true)]
+    #[case("192.168.1.10", "192.168.1.11", false)]
+    #[case("0.0.0.0/0", "203.0.113.1", true)]
+    #[case("10.0.0.0/8", "::ffff:10.1.2.3", true)]
+    #[case("2001:db8::/32", "2001:db8:1::1", true)]
+    #[case("2001:db8::/32", "2001:db9::1", false)]
+    #[case("2001:db8::/32", "10.1.2.3", false)]
+    fn should_check_network_contains_address(
+        #[case] network: &str,
+        #[case] address: &str,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(
+            IpNet::from_str(network).map(|network| network.contains(ip(address))),
+            Ok(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("10.0.0.0/33")]
+    #[case("2001:db8::/129")]
+    #[case("10.0.0/8")]
+    #[case("mtb.example.org")]
+    fn should_reject_invalid_network(#[case] network: &str) {
+        assert!(IpNet::from_str(network).is_err());
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[rstest]
+    // Untrusted peer, header ignored
+    #[case("203.0.113.9", "x-forwarded-for", "10.1.2.3", "203.0.113.9")]
+    #[case("172.16.0.2", "x-forwarded-for", "10.1.2.3", "10.1.2.3")]
+    // Spoofed leftmost entry is ignored
+    #[case("172.16.0.2", "x-forwarded-for", "10.9.9.9, 10.1.2.3", "10.1.2.3")]
+    // Chain of trusted proxies
+    #[case("172.16.0.2", "x-forwarded-for", "10.1.2.3, 172.16.0.3", "10.1.2.3")]
+    #[case("172.16.0.2", "x-forwarded-for", "unknown", "172.16.0.2")]
+    fn should_determine_client_ip(
+        #[case] peer: &str,
+        #[case] header: &'static str,
+        #[case] value: &'static str,
+        #[case] expected: &str,
+    ) {
+        let access = AccessControl::new(
+            vec![],
+            networks(&["172.16.0.0/12"]),
+            ForwardedHeader::XForwardedFor,
+        );
+
+        assert_eq!(
+            access.client_ip(ip(peer), &headers(header, value)),
+            ip(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("for=10.1.2.3;proto=https", "10.1.2.3")]
+    #[case("for=\"[2001:db8::1]:4711\"", "2001:db8::1")]
+    #[case("for=10.9.9.9, for=10.1.2.3:8080", "10.1.2.3")]
+    fn should_determine_client_ip_from_forwarded_header(
+        #[case] value: &'static str,
+        #[case] expected: &str,
+    ) {
+        let access = AccessControl::new(
+            vec![],
+            networks(&["172.16.0.0/12"]),
+            ForwardedHeader::Forwarded,
+        );
+
+        assert_eq!(
+            access.client_ip(ip("172.16.0.2"), &headers("forwarded", value)),
+            ip(expected)
+        );
+    }
+
+    #[rstest]
+    // Client forged 'Forwarded', proxy appended to 'X-Forwarded-For'
+    #[case(ForwardedHeader::XForwardedFor, "203.0.113.9")]
+    // Client forged 'X-Forwarded-For', proxy wrote 'Forwarded'
+    #[case(ForwardedHeader::Forwarded, "10.0.0.5")]
+    fn should_use_configured_forwarding_header_only(
+        #[case] forwarded_header: ForwardedHeader,
+        #[case] expected: &str,
+    ) {
+        let access = AccessControl::new(vec![], networks(&["172.16.0.0/12"]), forwarded_header);
+        let mut headers = HeaderMap::new();
+        headers.insert("forwarded", HeaderValue::from_static("for=10.0.0.5"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.9"));
+
+        assert_eq!(access.client_ip(ip("172.16.0.2"), &headers), ip(expected));
+    }
+
+    #[test]
+    fn should_allow_all_without_networks() {
+        assert!(is_allowed(&[], None));
+        assert!(is_allowed(&[], Some(ip("203.0.113.9"))));
+        assert!(!is_allowed(&networks(&["10.0.0.0/8"]), None));
+    }
+}
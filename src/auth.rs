@@ -1,7 +1,548 @@
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use bcrypt::HashParts;
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+
+use crate::access::{AccessControl, IpNet};
+use crate::cli::{AuthMode, Cli, IdentityMapping, IdentityNetworks};
+use crate::jwt::DynJwtValidator;
+use crate::lockout::Lockout;
+use crate::signature::RequestSignatures;
+use crate::tls::ClientCertificate;
+
+/// Authenticated sending system, used in logs and Kafka record headers
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity(pub String);
+
+impl Display for ClientIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Interval to check htpasswd files for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum number of cached successful verifications
+const MAX_CACHED_VERIFICATIONS: usize = 10_000;
+
+/// Permission to use an endpoint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Submit,
+    Delete,
+    Validate,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Submit, Role::Delete, Role::Validate];
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "submit" => Ok(Role::Submit),
+            "delete" => Ok(Role::Delete),
+            "validate" => Ok(Role::Validate),
+            other => Err(format!(
+                "unknown role '{other}', expected 'submit', 'delete' or 'validate'"
+            )),
+        }
+    }
+}
+
+/// Roles of the authenticated sending system
+#[derive(Clone, Debug, PartialEq)]
+pub struct Roles(pub Vec<Role>);
+
+impl Roles {
+    pub fn all() -> Self {
+        Roles(Role::ALL.to_vec())
+    }
+
+    pub fn contains_any(&self, roles: &[Role]) -> bool {
+        roles.iter().any(|role| self.0.contains(role))
+    }
+}
+
+/// User allowed to authenticate with HTTP Basic
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub hash: String,
+    pub roles: Roles,
+    pub networks: Vec<IpNet>,
+}
+
+impl FromStr for User {
+    type Err = String;
+
+    /// Parses an htpasswd line `name:bcrypt-hash` with optional roles `:submit,delete,validate`
+    /// and optional allowed networks `:10.0.0.0/8,2001:db8::/32`.
+    /// Users without roles are allowed to use all endpoints, users without networks all networks.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(4, ':');
+        let (Some(name), Some(hash)) = (parts.next(), parts.next()) else {
+            return Err("expected 'NAME:BCRYPT_HASH[:ROLES[:NETWORKS]]'".to_string());
+        };
+        if name.is_empty() {
+            return Err("user name must not be empty".to_string());
+        }
+        if HashParts::from_str(hash).is_err() {
+            return Err(format!("no valid BCrypt hash for user '{name}'"));
+        }
+        let roles = match parts.next() {
+            Some(roles) if !roles.is_empty() => Roles(
+                roles
+                    .split(',')
+                    .map(Role::from_str)
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            _ => Roles::all(),
+        };
+        let networks = match parts.next() {
+            Some(networks) => networks
+                .split(',')
+                .map(IpNet::from_str)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![],
+        };
+        Ok(User {
+            name: name.to_string(),
+            hash: hash.to_string(),
+            roles,
+            networks,
+        })
+    }
+}
+
+/// Parses users file content, ignoring empty lines and comments
+pub fn parse_users(content: &str) -> (Vec<User>, Vec<String>) {
+    let mut users = Vec::new();
+    let mut errors = Vec::new();
+    for (line_number, line) in content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+    {
+        match User::from_str(line) {
+            Ok(user) => users.push(user),
+            Err(err) => errors.push(format!("line {line_number}: {err}")),
+        }
+    }
+    (users, errors)
+}
+
+/// Checks that the configured authentication mode can be used
+pub fn validate_config(cli: &Cli) -> Result<(), String> {
+    if cli.auth_mode.uses_basic_auth() {
+        match &cli.token {
+            Some(token) if is_valid_brypt_hash(token) || is_token_file(token) => {}
+            Some(_) => {
+                return Err(
+                    "given token is neither a valid BCrypt token nor an htpasswd file".into(),
+                );
+            }
+            None if cli.users_file.is_some() => {}
+            None => return Err("no token or users file given".into()),
+        }
+    }
+
+    if cli.auth_mode == AuthMode::Jwt && cli.jwt_jwks.is_none() {
+        return Err("JWT authentication requires a JWKS".into());
+    }
+
+    if cli.auth_mode.uses_jwt() && cli.jwt_jwks.is_some() && cli.jwt_roles_claim.is_none() {
+        return Err("JWT authentication requires a roles claim".into());
+    }
+
+    if cli.auth_mode.uses_client_cert() && cli.tls_client_ca.is_none() {
+        return Err("client certificate authentication requires a client CA".into());
+    }
+
+    Ok(())
+}
+
+/// Security token given as path to an htpasswd file instead of a bcrypt hash
+pub fn is_token_file(token: &str) -> bool {
+    !is_valid_brypt_hash(token) && Path::new(token).is_file()
+}
+
+/// Sources of users: the security token, if not given as file, and htpasswd files
+#[derive(Default)]
+struct UserSources {
+    token_user: Option<User>,
+    files: Vec<PathBuf>,
+}
+
+impl UserSources {
+    fn new(cli: &Cli) -> Self {
+        let mut sources = UserSources::default();
+        if let Some(token) = &cli.token {
+            if is_token_file(token) {
+                sources.files.push(PathBuf::from(token));
+            } else {
+                let (name, hash) = split_username_password(token);
+                sources.token_user = Some(User {
+                    name,
+                    hash,
+                    roles: Roles::all(),
+                    networks: vec![],
+                });
+            }
+        }
+        if let Some(users_file) = &cli.users_file {
+            sources.files.push(users_file.clone());
+        }
+        sources
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+
+    /// Returns all valid users and errors for invalid or duplicate entries.
+    /// Fails if a file cannot be read.
+    fn read(&self) -> Result<(Vec<User>, Vec<String>), String> {
+        let mut users = Vec::new();
+        let mut errors = Vec::new();
+        users.extend(self.token_user.clone());
+        for path in &self.files {
+            let content = fs::read_to_string(path)
+                .map_err(|err| format!("Cannot read users file '{}': {err}", path.display()))?;
+            let (file_users, file_errors) = parse_users(&content);
+            errors.extend(
+                file_errors
+                    .into_iter()
+                    .map(|err| format!("Invalid entry in '{}', {err}", path.display())),
+            );
+            for user in file_users {
+                if users
+                    .iter()
+                    .any(|existing: &User| existing.name == user.name)
+                {
+                    errors.push(format!(
+                        "Duplicate user '{}' in '{}'",
+                        user.name,
+                        path.display()
+                    ));
+                } else {
+                    users.push(user);
+                }
+            }
+        }
+        Ok((users, errors))
+    }
+}
+
+/// Authentication methods used by the route middleware
+#[derive(Clone)]
+pub struct AuthState {
+    pub users: DynUsers,
+    pub jwt: Option<DynJwtValidator>,
+    pub lockout: Arc<Lockout>,
+    pub access: Arc<AccessControl>,
+    pub signatures: Option<Arc<RequestSignatures>>,
+}
+
+pub type DynUsers = Arc<Users>;
+
+/// Successfully verified credentials, keyed by an HMAC of the authorization header.
+/// The HMAC key is random per process, so neither plaintext nor a reusable hash is stored.
+struct VerificationCache {
+    key: Option<hmac::Key>,
+    ttl: Duration,
+    entries: Mutex<CachedVerifications>,
+}
+
+struct CachedVerification {
+    name: String,
+    hash: String,
+    expires_at: Instant,
+}
+
+/// All entries share the same TTL, so they expire in insertion order
+#[derive(Default)]
+struct CachedVerifications {
+    verifications: HashMap<Vec<u8>, CachedVerification>,
+    expiry: VecDeque<(Vec<u8>, Instant)>,
+}
+
+impl CachedVerifications {
+    /// Removes expired entries and the oldest entries above the size limit
+    fn evict(&mut self, now: Instant) {
+        while let Some((_, expires_at)) = self.expiry.front()
+            && (*expires_at < now || self.expiry.len() >= MAX_CACHED_VERIFICATIONS)
+        {
+            let Some((tag, expires_at)) = self.expiry.pop_front() else {
+                return;
+            };
+            // Entry may have been replaced by a newer verification
+            if self
+                .verifications
+                .get(&tag)
+                .is_some_and(|entry| entry.expires_at == expires_at)
+            {
+                self.verifications.remove(&tag);
+            }
+        }
+    }
+}
+
+impl VerificationCache {
+    fn new(ttl: Duration) -> Self {
+        let key = if ttl.is_zero() {
+            None
+        } else {
+            ring::rand::generate::<[u8; 32]>(&SystemRandom::new())
+                .ok()
+                .map(|key| hmac::Key::new(hmac::HMAC_SHA256, &key.expose()))
+        };
+        Self {
+            key,
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    fn tag(&self, auth_header: &str) -> Option<Vec<u8>> {
+        self.key
+            .as_ref()
+            .map(|key| hmac::sign(key, auth_header.as_bytes()).as_ref().to_vec())
+    }
+
+    /// Returns name and bcrypt hash of the user verified with this tag
+    fn get(&self, tag: &[u8]) -> Option<(String, String)> {
+        let entries = self.entries.lock().ok()?;
+        let entry = entries.verifications.get(tag)?;
+        if entry.expires_at < Instant::now() {
+            return None;
+        }
+        Some((entry.name.clone(), entry.hash.clone()))
+    }
+
+    fn insert(&self, tag: Vec<u8>, user: &User) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        entries.evict(now);
+
+        let expires_at = now + self.ttl;
+        entries.expiry.push_back((tag.clone(), expires_at));
+        entries.verifications.insert(
+            tag,
+            CachedVerification {
+                name: user.name.clone(),
+                hash: user.hash.clone(),
+                expires_at,
+            },
+        );
+    }
+}
+
+/// Maximum number of concurrent bcrypt verifications on the blocking thread pool,
+/// one per available CPU
+fn max_concurrent_verifications() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Users allowed to authenticate with HTTP Basic, reloaded if htpasswd files change
+pub struct Users {
+    sources: RwLock<UserSources>,
+    current: RwLock<Vec<User>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+    cache: VerificationCache,
+    verifications: Semaphore,
+}
+
+impl Users {
+    #[cfg(test)]
+    pub fn new(users: Vec<User>) -> Self {
+        Users {
+            sources: RwLock::default(),
+            current: RwLock::new(users),
+            modified: Mutex::default(),
+            cache: VerificationCache::new(Duration::from_mins(1)),
+            verifications: Semaphore::new(max_concurrent_verifications()),
+        }
+    }
+
+    /// Loads users given by security token and users file. Fails on any invalid entry.
+    pub fn from_config(cli: &Cli) -> Result<Self, String> {
+        Self::from_sources(
+            UserSources::new(cli),
+            Duration::from_secs(cli.auth_cache_ttl),
+        )
+    }
+
+    fn from_sources(sources: UserSources, cache_ttl: Duration) -> Result<Self, String> {
+        let modified = sources.modified();
+        let (users, errors) = sources.read()?;
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+        Ok(Users {
+            sources: RwLock::new(sources),
+            current: RwLock::new(users),
+            modified: Mutex::new(modified),
+            cache: VerificationCache::new(cache_ttl),
+            verifications: Semaphore::new(max_concurrent_verifications()),
+        })
+    }
+
+    /// Returns true if changed files have been loaded.
+    /// Invalid entries are logged and rejected, unreadable files keep the current users.
+    pub fn reload_if_changed(&self) -> bool {
+        self.reload(false)
+    }
+
+    /// Replaces the sources after the configuration has been reloaded and loads all users
+    pub fn reload_config(&self, cli: &Cli) -> bool {
+        if let Ok(mut sources) = self.sources.write() {
+            *sources = UserSources::new(cli);
+        }
+        self.reload(true)
+    }
+
+    fn reload(&self, force: bool) -> bool {
+        let Ok(sources) = self.sources.read() else {
+            return false;
+        };
+        let modified = sources.modified();
+        let Ok(mut last_modified) = self.modified.lock() else {
+            return false;
+        };
+        if !force && *last_modified == modified {
+            return false;
+        }
+
+        match sources.read() {
+            Ok((users, errors)) => {
+                for err in errors {
+                    log::warn!("Rejected user on reload: {err}");
+                }
+                let count = users.len();
+                if let Ok(mut current) = self.current.write() {
+                    *current = users;
+                }
+                *last_modified = modified;
+                log::info!("Reloaded users, {count} users available");
+                true
+            }
+            Err(err) => {
+                log::error!("Cannot reload users, keeping current users: {err}");
+                false
+            }
+        }
+    }
+
+    /// Number of currently available users
+    pub fn count(&self) -> usize {
+        self.current
+            .read()
+            .map(|users| users.len())
+            .unwrap_or_default()
+    }
+
+    pub fn user(&self, name: &str) -> Option<User> {
+        self.current
+            .read()
+            .ok()?
+            .iter()
+            .find(|user| user.name == name)
+            .cloned()
+    }
+
+    /// Returns the user if the HTTP Basic credentials are valid.
+    /// bcrypt verification runs on the blocking thread pool, successful verifications are cached
+    /// as long as the user's hash is unchanged.
+    pub async fn authenticate(&self, auth_header: &str) -> Option<User> {
+        let tag = self.cache.tag(auth_header);
+        if let Some((name, hash)) = tag.as_deref().and_then(|tag| self.cache.get(tag))
+            && let Some(user) = self.user(&name)
+            && user.hash == hash
+        {
+            return Some(user);
+        }
+
+        let (username, _) = decode_basic_auth(auth_header)?;
+        let user = self.user(&username)?;
+
+        let _permit = self.verifications.acquire().await.ok()?;
+        let auth_header = auth_header.to_string();
+        let expected_token = format!("{}:{}", user.name, user.hash);
+        let valid =
+            tokio::task::spawn_blocking(move || check_basic_auth(&auth_header, &expected_token))
+                .await
+                .unwrap_or(false);
+        if !valid {
+            return None;
+        }
+
+        if let Some(tag) = tag {
+            self.cache.insert(tag, &user);
+        }
+        Some(user)
+    }
+}
+
+pub async fn reload_periodically(users: Weak<Users>) {
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        match users.upgrade() {
+            Some(users) => {
+                tokio::task::spawn_blocking(move || users.reload_if_changed())
+                    .await
+                    .ok();
+            }
+            None => return,
+        }
+    }
+}
+
+/// Returns identity, roles and allowed networks of the first allow-list entry matching
+/// the client certificate
+pub fn client_cert_identity(
+    client_certificate: &ClientCertificate,
+    allow_list: &[IdentityMapping],
+) -> Option<(ClientIdentity, Roles, Vec<IpNet>)> {
+    allow_list
+        .iter()
+        .find(|mapping| client_certificate.matches(&mapping.name))
+        .map(|mapping| {
+            (
+                ClientIdentity(mapping.identity.clone()),
+                mapping.roles.clone(),
+                mapping.networks.clone(),
+            )
+        })
+}
+
+/// Returns the networks allowed for a bearer token identity, all networks if not configured
+pub fn identity_networks(identity: &ClientIdentity, mappings: &[IdentityNetworks]) -> Vec<IpNet> {
+    mappings
+        .iter()
+        .filter(|mapping| mapping.identity == identity.0)
+        .flat_map(|mapping| mapping.networks.iter().copied())
+        .collect()
+}
 
 pub fn split_username_password(auth: &str) -> (String, String) {
     let split = auth.split(':').collect::<Vec<_>>();
@@ -16,25 +557,30 @@ pub fn is_valid_brypt_hash(auth: &str) -> bool {
     HashParts::from_str(&hash).is_ok()
 }
 
-#[allow(clippy::module_name_repetitions)]
-pub fn check_basic_auth(auth_header: &str, expected_token: &str) -> bool {
+/// Returns username and password of an HTTP Basic authorization header
+fn decode_basic_auth(auth_header: &str) -> Option<(String, String)> {
     let split = auth_header.split(' ').collect::<Vec<_>>();
     if split.len() == 2
         && split.first().map(|first| first.to_lowercase()) == Some("basic".into())
         && let Ok(auth) = BASE64_STANDARD.decode(split.last().unwrap_or(&""))
         && let Ok(auth) = String::from_utf8(auth)
     {
-        let split_token = expected_token.split(':').collect::<Vec<_>>();
-        let expected_username = if split_token.len() == 2 {
-            split_token.first().unwrap_or(&"token")
-        } else {
-            &"token"
-        };
-        let expected_token = split_token.get(1).unwrap_or(&expected_token);
+        return Some(split_username_password(&auth));
+    }
+    None
+}
 
-        let (username, hash) = &split_username_password(&auth);
+/// Returns the username of an HTTP Basic authorization header
+pub fn basic_auth_username(auth_header: &str) -> Option<String> {
+    decode_basic_auth(auth_header).map(|(username, _)| username)
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub fn check_basic_auth(auth_header: &str, expected_token: &str) -> bool {
+    if let Some((username, password)) = decode_basic_auth(auth_header) {
+        let (expected_username, expected_token) = split_username_password(expected_token);
         if username == expected_username
-            && let Ok(true) = bcrypt::verify(hash, expected_token)
+            && let Ok(true) = bcrypt::verify(password, &expected_token)
         {
             return true;
         }
@@ -45,8 +591,20 @@ pub fn check_basic_auth(auth_header: &str, expected_token: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use crate::auth::{check_basic_auth, is_valid_brypt_hash, split_username_password};
+    use crate::access::IpNet;
+    use crate::auth::{
+        ClientIdentity, MAX_CACHED_VERIFICATIONS, Role, Roles, User, UserSources, Users,
+        VerificationCache, check_basic_auth, client_cert_identity, identity_networks,
+        is_valid_brypt_hash, parse_users, split_username_password, validate_config,
+    };
+    use crate::cli::{CertificateName, Cli, IdentityMapping, IdentityNetworks};
+    use crate::test_util::TempDir;
+    use crate::tls::ClientCertificate;
+    use clap::Parser;
     use rstest::rstest;
+    use std::fs;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
 
     // plain text value 'very-secret'
     const EXPECTED_TOKEN: &str = "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG";
@@ -143,4 +701,360 @@ mod tests {
     fn should_split_username_tokenhash(#[case] input: &str, #[case] expected: (String, String)) {
         assert_eq!(split_username_password(input), expected);
     }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_map_allow_listed_client_certificate_to_identity() {
+        let allow_list = vec![
+            IdentityMapping {
+                identity: "mtb-doc".into(),
+                roles: Roles::all(),
+                networks: vec![],
+                name: CertificateName::DnsName("mtb-documentation.example.org".into()),
+            },
+            IdentityMapping {
+                identity: "pathology".into(),
+                roles: Roles(vec![Role::Submit]),
+                networks: vec![IpNet::from_str("10.1.2.0/24").expect("valid network")],
+                name: CertificateName::CommonName("pathology".into()),
+            },
+        ];
+
+        let client_certificate = ClientCertificate {
+            common_name: Some("mtb-documentation".into()),
+            dns_names: vec!["mtb-documentation.example.org".into()],
+            uris: vec![],
+        };
+        assert_eq!(
+            client_cert_identity(&client_certificate, &allow_list),
+            Some((ClientIdentity("mtb-doc".into()), Roles::all(), vec![]))
+        );
+
+        let client_certificate = ClientCertificate {
+            common_name: Some("pathology".into()),
+            ..ClientCertificate::default()
+        };
+        assert_eq!(
+            client_cert_identity(&client_certificate, &allow_list),
+            Some((
+                ClientIdentity("pathology".into()),
+                Roles(vec![Role::Submit]),
+                vec![IpNet::from_str("10.1.2.0/24").expect("valid network")]
+            ))
+        );
+
+        let client_certificate = ClientCertificate {
+            common_name: Some("other".into()),
+            ..ClientCertificate::default()
+        };
+        assert_eq!(client_cert_identity(&client_certificate, &allow_list), None);
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_return_networks_of_bearer_token_identity() {
+        let mappings = vec![IdentityNetworks {
+            identity: "mtb-doc".into(),
+            networks: vec![IpNet::from_str("10.1.2.0/24").expect("valid network")],
+        }];
+
+        assert_eq!(
+            identity_networks(&ClientIdentity("mtb-doc".into()), &mappings),
+            vec![IpNet::from_str("10.1.2.0/24").expect("valid network")]
+        );
+        assert!(identity_networks(&ClientIdentity("pathology".into()), &mappings).is_empty());
+    }
+
+    #[rstest]
+    #[case(&format!("mtb-doc:{EXPECTED_TOKEN}"), Roles::all())]
+    #[case(&format!("pathology:{EXPECTED_TOKEN}:submit"), Roles(vec![Role::Submit]))]
+    #[case(&format!("dpo:{EXPECTED_TOKEN}:delete, validate"), Roles(vec![Role::Delete, Role::Validate]))]
+    fn should_parse_user_with_roles(#[case] input: &str, #[case] expected: Roles) {
+        assert_eq!(User::from_str(input).map(|user| user.roles), Ok(expected));
+    }
+
+    #[rstest]
+    #[case(&format!("mtb-doc:{EXPECTED_TOKEN}::10.0.0.0/8,2001:db8::/32"), Roles::all(), "10.0.0.0/8,2001:db8::/32")]
+    #[case(&format!("dpo:{EXPECTED_TOKEN}:delete:192.168.1.10"), Roles(vec![Role::Delete]), "192.168.1.10/32")]
+    #[case(&format!("pathology:{EXPECTED_TOKEN}:submit"), Roles(vec![Role::Submit]), "")]
+    fn should_parse_user_with_networks(
+        #[case] input: &str,
+        #[case] roles: Roles,
+        #[case] networks: &str,
+    ) {
+        assert_eq!(
+            User::from_str(input).map(|user| (
+                user.roles,
+                user.networks
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )),
+            Ok((roles, networks.to_string()))
+        );
+    }
+
+    #[rstest]
+    #[case(&format!("mtb-doc:{EXPECTED_TOKEN}::10.0.0.0/33"))]
+    #[case(EXPECTED_TOKEN)]
+    #[case("mtb-doc:very-secret")]
+    #[case(&format!(":{EXPECTED_TOKEN}"))]
+    #[case(&format!("mtb-doc:{EXPECTED_TOKEN}:admin"))]
+    fn should_reject_invalid_user(#[case] input: &str) {
+        assert!(User::from_str(input).is_err());
+    }
+
+    #[test]
+    fn should_parse_users_file_content() {
+        let content = format!(
+            "# MTB documentation\nmtb-doc:{EXPECTED_TOKEN}\n\npathology:{EXPECTED_TOKEN}:submit\n"
+        );
+
+        let (users, errors) = parse_users(&content);
+
+        assert_eq!(
+            users.into_iter().map(|user| user.name).collect::<Vec<_>>(),
+            vec!["mtb-doc".to_string(), "pathology".to_string()]
+        );
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn should_report_line_of_invalid_user() {
+        let content = format!("mtb-doc:{EXPECTED_TOKEN}\npathology:very-secret\n");
+
+        let (users, errors) = parse_users(&content);
+
+        assert_eq!(users.len(), 1);
+        assert_eq!(
+            errors,
+            vec!["line 2: no valid BCrypt hash for user 'pathology'".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_reload_changed_users_file() {
+        let dir = TempDir::new();
+        let path = dir.file("users", &format!("mtb-doc:{EXPECTED_TOKEN}\n"));
+
+        let users = Users::from_sources(
+            UserSources {
+                token_user: None,
+                files: vec![path.clone()],
+            },
+            Duration::ZERO,
+        )
+        .expect("users loaded");
+        // mtb-doc:very-secret
+        assert!(
+            users
+                .authenticate("Basic bXRiLWRvYzp2ZXJ5LXNlY3JldA==")
+                .await
+                .is_some()
+        );
+        assert!(!users.reload_if_changed());
+
+        fs::write(
+            &path,
+            format!("pathology:{EXPECTED_TOKEN}\nmtb-doc:very-secret\n"),
+        )
+        .expect("file written");
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now() + Duration::from_mins(1)))
+            .expect("modification time set");
+
+        assert!(users.reload_if_changed());
+        // invalid entry is rejected
+        assert!(
+            users
+                .authenticate("Basic bXRiLWRvYzp2ZXJ5LXNlY3JldA==")
+                .await
+                .is_none()
+        );
+        // pathology:very-secret
+        assert!(
+            users
+                .authenticate("Basic cGF0aG9sb2d5OnZlcnktc2VjcmV0")
+                .await
+                .is_some()
+        );
+
+        fs::remove_file(&path).expect("file removed");
+        assert!(!users.reload_if_changed());
+        // unreadable file keeps current users
+        assert!(
+            users
+                .authenticate("Basic cGF0aG9sb2d5OnZlcnktc2VjcmV0")
+                .await
+                .is_some()
+        );
+    }
+
+    #[rstest]
+    // mtb-doc:very-secret
+    #[case("Basic bXRiLWRvYzp2ZXJ5LXNlY3JldA==", Some("mtb-doc"))]
+    // pathology:very-secret
+    #[case("Basic cGF0aG9sb2d5OnZlcnktc2VjcmV0", Some("pathology"))]
+    // mtb-doc:123456789
+    #[case("Basic bXRiLWRvYzoxMjM0NTY3ODk=", None)]
+    // other:very-secret
+    #[case("Basic b3RoZXI6dmVyeS1zZWNyZXQ=", None)]
+    #[tokio::test]
+    async fn should_authenticate_user(#[case] auth_header: &str, #[case] expected: Option<&str>) {
+        let users = Users::new(vec![
+            User {
+                name: "mtb-doc".into(),
+                hash: EXPECTED_TOKEN.into(),
+                roles: Roles::all(),
+                networks: vec![],
+            },
+            User {
+                name: "pathology".into(),
+                hash: EXPECTED_TOKEN.into(),
+                roles: Roles(vec![Role::Submit]),
+                networks: vec![],
+            },
+        ]);
+
+        assert_eq!(
+            users.authenticate(auth_header).await.map(|user| user.name),
+            expected.map(ToString::to_string)
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_cache_successful_verification_while_hash_is_unchanged() {
+        let users = Users::new(vec![User {
+            name: "mtb-doc".into(),
+            hash: EXPECTED_TOKEN.into(),
+            roles: Roles::all(),
+            networks: vec![],
+        }]);
+        let cached = |users: &Users| {
+            users
+                .cache
+                .entries
+                .lock()
+                .map(|entries| entries.verifications.len())
+                .ok()
+        };
+
+        // mtb-doc:123456789
+        assert!(
+            users
+                .authenticate("Basic bXRiLWRvYzoxMjM0NTY3ODk=")
+                .await
+                .is_none()
+        );
+        assert_eq!(cached(&users), Some(0));
+
+        // mtb-doc:very-secret
+        assert!(
+            users
+                .authenticate("Basic bXRiLWRvYzp2ZXJ5LXNlY3JldA==")
+                .await
+                .is_some()
+        );
+        assert_eq!(cached(&users), Some(1));
+
+        if let Ok(mut current) = users.current.write() {
+            // token changed on reload
+            current[0].hash = bcrypt::hash("other-secret", 4).expect("token hashed");
+        }
+        assert!(
+            users
+                .authenticate("Basic bXRiLWRvYzp2ZXJ5LXNlY3JldA==")
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn should_evict_oldest_verification_if_cache_is_full() {
+        let cache = VerificationCache::new(Duration::from_mins(1));
+        let user = User {
+            name: "mtb-doc".into(),
+            hash: EXPECTED_TOKEN.into(),
+            roles: Roles::all(),
+            networks: vec![],
+        };
+
+        for index in 0..=MAX_CACHED_VERIFICATIONS {
+            cache.insert(index.to_be_bytes().to_vec(), &user);
+        }
+
+        assert!(cache.get(&0_usize.to_be_bytes()).is_none());
+        assert!(cache.get(&MAX_CACHED_VERIFICATIONS.to_be_bytes()).is_some());
+        assert_eq!(
+            cache
+                .entries
+                .lock()
+                .map(|entries| entries.verifications.len())
+                .ok(),
+            Some(MAX_CACHED_VERIFICATIONS)
+        );
+    }
+
+    #[test]
+    fn should_evict_expired_verifications() {
+        let cache = VerificationCache::new(Duration::from_millis(1));
+        let user = User {
+            name: "mtb-doc".into(),
+            hash: EXPECTED_TOKEN.into(),
+            roles: Roles::all(),
+            networks: vec![],
+        };
+
+        cache.insert(b"first".to_vec(), &user);
+        std::thread::sleep(Duration::from_millis(5));
+        cache.insert(b"second".to_vec(), &user);
+
+        assert_eq!(
+            cache
+                .entries
+                .lock()
+                .map(|entries| entries.verifications.len())
+                .ok(),
+            Some(1)
+        );
+    }
+
+    #[rstest]
+    #[case(&["--auth-mode", "jwt"], false)]
+    #[case(&["--auth-mode", "any"], false)]
+    #[case(&["--auth-mode", "jwt", "--jwt-roles-claim", "scope"], true)]
+    #[case(&["--auth-mode", "basic"], true)]
+    fn should_require_roles_claim_for_jwt(#[case] args: &[&str], #[case] valid: bool) {
+        let cli = Cli::try_parse_from(
+            [
+                "mv64e-rest-to-kafka-gateway",
+                "--token",
+                "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG",
+                "--tls-cert",
+                "server.pem",
+                "--tls-key",
+                "server.key",
+                "--tls-client-ca",
+                "ca.pem",
+                "--jwt-jwks",
+                "jwks.json",
+                "--jwt-issuer",
+                "https://idp.example.org/realms/mv64e",
+                "--jwt-audience",
+                "mv64e-gateway",
+            ]
+            .iter()
+            .chain(args),
+        );
+
+        assert_eq!(
+            cli.map(|cli| validate_config(&cli).is_ok()).ok(),
+            Some(valid)
+        );
+    }
 }
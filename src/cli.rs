@@ -1,9 +1,29 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::access::IpNet;
+use crate::auth::{Role, Roles};
 
 #[derive(Parser)]
 #[command(author, version, about)]
 #[command(arg_required_else_help(true))]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(
+        long,
+        env = "CONFIG_FILE",
+        help = "TOML configuration file, overridden by options and env vars. Reloaded on SIGHUP"
+    )]
+    pub config: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "LOG_LEVEL",
+        help = "Log level, e.g. 'info' or 'debug'. Reloaded on SIGHUP"
+    )]
+    pub log_level: Option<tracing::Level>,
     #[arg(
         long,
         env = "LISTEN",
@@ -11,13 +31,158 @@ pub struct Cli {
         help = "Address and port for HTTP requests"
     )]
     pub listen: String,
+    #[arg(
+        long,
+        env = "METRICS_LISTEN",
+        help = "Address and port for a separate metrics endpoint. Served with HTTP requests if not set"
+    )]
+    pub metrics_listen: Option<String>,
+    #[arg(
+        long,
+        env = "TLS_CERT",
+        requires = "tls_key",
+        help = "Certificate file for HTTPS. Reloaded if changed"
+    )]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "TLS_KEY",
+        requires = "tls_cert",
+        help = "Key file for HTTPS. Reloaded if changed"
+    )]
+    pub tls_key: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "TLS_CLIENT_CA",
+        requires = "tls_cert",
+        help = "CA file to require and verify client certificates for HTTPS"
+    )]
+    pub tls_client_ca: Option<PathBuf>,
+    #[arg(
+        long = "tls-client-identity",
+        env = "TLS_CLIENT_IDENTITIES",
+        value_name = "IDENTITY[:ROLES[:NETWORKS]]=cn|dns|uri:VALUE",
+        value_delimiter = ',',
+        value_parser = parse_identity_mapping,
+        help = "Allowed client certificate and the identity it is mapped to, e.g. 'mtb-doc:submit+validate:10.0.0.0/8=dns:mtb.example.org'. All roles and networks are allowed if not set"
+    )]
+    pub tls_client_identities: Vec<IdentityMapping>,
+    #[arg(
+        long = "allowed-network",
+        env = "ALLOWED_NETWORKS",
+        value_delimiter = ',',
+        help = "Networks allowed to send requests, e.g. '10.0.0.0/8'. All networks are allowed if not set"
+    )]
+    pub allowed_networks: Vec<IpNet>,
+    #[arg(
+        long = "trusted-proxy",
+        env = "TRUSTED_PROXIES",
+        value_delimiter = ',',
+        help = "Networks of reverse proxies whose forwarding header is used"
+    )]
+    pub trusted_proxies: Vec<IpNet>,
+    #[arg(
+        long,
+        env = "FORWARDED_HEADER",
+        default_value = "x-forwarded-for",
+        help = "Header with the client address written by trusted proxies, the other header is ignored"
+    )]
+    pub forwarded_header: ForwardedHeader,
     #[arg(
         long,
         alias = "security-token",
         env = "SECURITY_TOKEN",
-        help = "bcrypt hashed Security Token"
+        hide_default_value = true,
+        hide_env_values = true,
+        help = "bcrypt hashed Security Token or htpasswd file. Required for auth mode 'basic' and 'any'"
+    )]
+    pub token: Option<String>,
+    #[arg(
+        long,
+        env = "USERS_FILE",
+        help = "htpasswd file with bcrypt hashed tokens and optional roles of additional users"
+    )]
+    pub users_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "AUTH_CACHE_TTL",
+        default_value = "60",
+        help = "Seconds to cache successful HTTP Basic verifications. Disabled with 0"
+    )]
+    pub auth_cache_ttl: u64,
+    #[arg(
+        long,
+        env = "AUTH_MAX_FAILURES",
+        default_value = "5",
+        help = "Failed authentications per client IP or username before lockout. Disabled with 0"
+    )]
+    pub auth_max_failures: u32,
+    #[arg(
+        long,
+        env = "AUTH_LOCKOUT",
+        default_value = "60",
+        help = "Seconds of the first lockout, doubled with every further failure up to one hour"
+    )]
+    pub auth_lockout: u64,
+    #[arg(
+        long,
+        env = "AUTH_MODE",
+        value_enum,
+        ignore_case = true,
+        default_value = "basic",
+        help = "Authentication of sending systems"
+    )]
+    pub auth_mode: AuthMode,
+    #[arg(
+        long,
+        env = "JWT_JWKS",
+        requires_all = ["jwt_issuer", "jwt_audience"],
+        help = "URL or file of the JWKS to verify bearer tokens"
+    )]
+    pub jwt_jwks: Option<String>,
+    #[arg(long, env = "JWT_ISSUER", help = "Expected issuer of bearer tokens")]
+    pub jwt_issuer: Option<String>,
+    #[arg(
+        long,
+        env = "JWT_AUDIENCE",
+        help = "Expected audience of bearer tokens"
+    )]
+    pub jwt_audience: Option<String>,
+    #[arg(
+        long,
+        env = "JWT_IDENTITY_CLAIM",
+        default_value = "sub",
+        help = "Bearer token claim used as client identity"
+    )]
+    pub jwt_identity_claim: String,
+    #[arg(
+        long,
+        env = "JWT_ROLES_CLAIM",
+        help = "Bearer token claim with roles, e.g. 'realm_access.roles'. Required for JWT authentication"
+    )]
+    pub jwt_roles_claim: Option<String>,
+    #[arg(
+        long = "jwt-identity-network",
+        env = "JWT_IDENTITY_NETWORKS",
+        value_name = "IDENTITY=NETWORKS",
+        value_delimiter = ',',
+        value_parser = parse_identity_networks,
+        help = "Networks allowed for a bearer token identity, e.g. 'mtb-doc=10.0.0.0/8+2001:db8::/32'. All networks are allowed if not set"
+    )]
+    pub jwt_identity_networks: Vec<IdentityNetworks>,
+    #[arg(
+        long,
+        env = "SIGNATURE_SECRETS",
+        help = "File with HMAC secrets of sending systems as 'identity:secret'. Requests must be signed if set"
+    )]
+    pub signature_secrets: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "SIGNATURE_MAX_AGE",
+        default_value = "300",
+        help = "Seconds a request signature timestamp may differ from the current time"
     )]
-    pub token: String,
+    pub signature_max_age: u64,
     #[arg(
         long,
         alias = "kafka-servers",
@@ -34,6 +199,41 @@ pub struct Cli {
         help = "Kafka Topic"
     )]
     pub topic: String,
+    #[arg(
+        long,
+        env = "KAFKA_STARTUP_CHECK",
+        value_enum,
+        ignore_case = true,
+        default_value = "degraded",
+        help = "Check Kafka connection and topic on startup"
+    )]
+    pub startup_check: StartupCheck,
+    #[arg(
+        long,
+        env = "KAFKA_CREATE_TOPIC",
+        help = "Create Kafka topic on startup if it does not exist"
+    )]
+    pub create_topic: bool,
+    #[arg(
+        long,
+        env = "KAFKA_TOPIC_PARTITIONS",
+        default_value = "1",
+        help = "Number of partitions of a created Kafka topic"
+    )]
+    pub topic_partitions: i32,
+    #[arg(
+        long,
+        env = "KAFKA_TOPIC_REPLICATION_FACTOR",
+        default_value = "1",
+        help = "Replication factor of a created Kafka topic"
+    )]
+    pub topic_replication_factor: i32,
+    #[arg(
+        long,
+        env = "KAFKA_TOPIC_COMPACT",
+        help = "Use 'cleanup.policy=compact' for a created Kafka topic"
+    )]
+    pub topic_compact: bool,
     #[arg(
         long,
         env = "KAFKA_SSL_CA_FILE",
@@ -52,8 +252,113 @@ pub struct Cli {
         help = "Key file for SSL connection to Kafka"
     )]
     pub ssl_key_file: Option<String>,
-    #[arg(long, env = "KAFKA_SSL_KEY_PASSWORD", help = "The SSL key password")]
+    #[arg(
+        long,
+        env = "KAFKA_SSL_KEY_PASSWORD",
+        hide_default_value = true,
+        hide_env_values = true,
+        help = "The SSL key password"
+    )]
     pub ssl_key_password: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SECURITY_PROTOCOL",
+        value_enum,
+        ignore_case = true,
+        help = "Security protocol for Kafka connection. Derived from SSL and SASL options if not set"
+    )]
+    pub security_protocol: Option<SecurityProtocol>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_MECHANISM",
+        value_enum,
+        ignore_case = true,
+        default_value = "plain",
+        help = "SASL mechanism for Kafka connection"
+    )]
+    pub sasl_mechanism: SaslMechanism,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_USERNAME",
+        help = "Username for SASL connection to Kafka"
+    )]
+    pub sasl_username: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_SASL_PASSWORD",
+        hide_default_value = true,
+        hide_env_values = true,
+        help = "Password for SASL connection to Kafka"
+    )]
+    pub sasl_password: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_OAUTH_TOKEN_ENDPOINT",
+        help = "OAuth token endpoint used with SASL mechanism 'oauthbearer'"
+    )]
+    pub oauth_token_endpoint: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_OAUTH_CLIENT_ID",
+        help = "OAuth client id used with SASL mechanism 'oauthbearer'"
+    )]
+    pub oauth_client_id: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_OAUTH_CLIENT_SECRET",
+        hide_default_value = true,
+        hide_env_values = true,
+        help = "OAuth client secret used with SASL mechanism 'oauthbearer'"
+    )]
+    pub oauth_client_secret: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_OAUTH_SCOPE",
+        help = "OAuth scope requested with SASL mechanism 'oauthbearer'"
+    )]
+    pub oauth_scope: Option<String>,
+    #[arg(
+        long,
+        env = "KAFKA_DELIVERY_GUARANTEE",
+        value_enum,
+        ignore_case = true,
+        default_value = "default",
+        help = "Delivery guarantee for Kafka records"
+    )]
+    pub delivery_guarantee: DeliveryGuarantee,
+    #[arg(
+        long = "producer-property",
+        value_name = "KEY=VALUE",
+        help = "Additional librdkafka producer property. Can also be set using env vars like 'KAFKA_PRODUCER_LINGER_MS'"
+    )]
+    pub producer_properties: Vec<String>,
+    #[arg(
+        long,
+        env = "KAFKA_QUEUE_TIMEOUT",
+        default_value = "1000",
+        help = "Time in milliseconds to wait for space in the producer queue"
+    )]
+    pub queue_timeout: u64,
+    #[arg(
+        long,
+        env = "SPOOL_DIR",
+        help = "Directory to spool records in if Kafka is not reachable"
+    )]
+    pub spool_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "SPOOL_MAX_RECORDS",
+        default_value = "10000",
+        help = "Maximum number of spooled records"
+    )]
+    pub spool_max_records: usize,
+    #[arg(
+        long,
+        env = "SPOOL_MAX_SIZE",
+        default_value = "1024",
+        help = "Maximum size of spooled records in MiB"
+    )]
+    pub spool_max_size: u64,
     #[arg(
         long,
         env = "SEND_ON_INVALID",
@@ -61,3 +366,364 @@ pub struct Cli {
     )]
     pub send_on_invalid: bool,
 }
+
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Start the gateway, used if no command is given
+    Serve,
+    /// Print a bcrypt hashed token to be used as security token or in a users file
+    HashToken {
+        #[arg(long, default_value = "token", help = "Username of the token")]
+        username: String,
+        #[arg(
+            long,
+            default_value = "12",
+            value_parser = clap::value_parser!(u32).range(4..=31),
+            help = "bcrypt cost, each increment doubles the time to verify the token"
+        )]
+        cost: u32,
+        #[arg(long, help = "Read the password from stdin instead of prompting")]
+        password_stdin: bool,
+    },
+    /// Check a password against the configured security token and users file
+    VerifyToken {
+        #[arg(long, default_value = "token", help = "Username of the token")]
+        username: String,
+        #[arg(long, help = "Read the password from stdin instead of prompting")]
+        password_stdin: bool,
+    },
+    /// Validate the configuration without starting the gateway
+    CheckConfig {
+        #[arg(long, help = "Also check Kafka reachability and topic metadata")]
+        kafka: bool,
+    },
+    /// Send MTB files to Kafka without the HTTP interface
+    Send {
+        #[arg(help = "MTB files to send, reads from stdin if none or '-' is given")]
+        files: Vec<PathBuf>,
+        #[arg(
+            long = "request-id",
+            value_name = "REQUEST_ID",
+            help = "Request ID of the record, given once per file in the same order"
+        )]
+        request_ids: Vec<String>,
+    },
+    /// Send delete requests for patients to Kafka without the HTTP interface
+    Delete {
+        #[arg(help = "Patient IDs to delete")]
+        patient_ids: Vec<String>,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "File with one patient ID per line, '-' to read from stdin"
+        )]
+        file: Option<PathBuf>,
+        #[arg(long, help = "List the patients without sending delete requests")]
+        dry_run: bool,
+        #[arg(long, short = 'y', help = "Do not ask for confirmation")]
+        yes: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SecurityProtocol {
+    #[value(name = "plaintext")]
+    Plaintext,
+    #[value(name = "ssl")]
+    Ssl,
+    #[value(name = "sasl_plaintext")]
+    SaslPlaintext,
+    #[value(name = "sasl_ssl")]
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    pub fn uses_ssl(self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+
+    pub fn uses_sasl(self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+}
+
+impl Display for SecurityProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecurityProtocol::Plaintext => write!(f, "plaintext"),
+            SecurityProtocol::Ssl => write!(f, "ssl"),
+            SecurityProtocol::SaslPlaintext => write!(f, "sasl_plaintext"),
+            SecurityProtocol::SaslSsl => write!(f, "sasl_ssl"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For: 192.0.2.1, 172.16.0.3`
+    #[value(name = "x-forwarded-for")]
+    XForwardedFor,
+    /// `Forwarded: for=192.0.2.1, for=172.16.0.3` as of RFC 7239
+    #[value(name = "forwarded")]
+    Forwarded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SaslMechanism {
+    #[value(name = "plain")]
+    Plain,
+    #[value(name = "scram-sha-256")]
+    ScramSha256,
+    #[value(name = "scram-sha-512")]
+    ScramSha512,
+    #[value(name = "oauthbearer")]
+    OAuthBearer,
+}
+
+impl Display for SaslMechanism {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaslMechanism::Plain => write!(f, "PLAIN"),
+            SaslMechanism::ScramSha256 => write!(f, "SCRAM-SHA-256"),
+            SaslMechanism::ScramSha512 => write!(f, "SCRAM-SHA-512"),
+            SaslMechanism::OAuthBearer => write!(f, "OAUTHBEARER"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum DeliveryGuarantee {
+    /// Use librdkafka defaults
+    #[value(name = "default")]
+    Default,
+    /// Idempotent producer, record is acknowledged by all in-sync replicas
+    #[value(name = "idempotent")]
+    Idempotent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum StartupCheck {
+    /// No check on startup
+    #[value(name = "disabled")]
+    Disabled,
+    /// Log failed check and start anyway
+    #[value(name = "degraded")]
+    Degraded,
+    /// Do not start on failed check
+    #[value(name = "fail-fast")]
+    FailFast,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum AuthMode {
+    /// HTTP Basic authentication with security token
+    #[value(name = "basic")]
+    Basic,
+    /// Allow-listed TLS client certificate
+    #[value(name = "client-cert")]
+    ClientCert,
+    /// Bearer token issued by an OIDC identity provider
+    #[value(name = "jwt")]
+    Jwt,
+    /// Allow-listed TLS client certificate, bearer token if configured or HTTP Basic authentication
+    #[value(name = "any")]
+    Any,
+}
+
+impl AuthMode {
+    pub fn uses_basic_auth(self) -> bool {
+        matches!(self, AuthMode::Basic | AuthMode::Any)
+    }
+
+    pub fn uses_client_cert(self) -> bool {
+        matches!(self, AuthMode::ClientCert | AuthMode::Any)
+    }
+
+    pub fn uses_jwt(self) -> bool {
+        matches!(self, AuthMode::Jwt | AuthMode::Any)
+    }
+}
+
+/// Part of a client certificate used to identify a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum CertificateName {
+    CommonName(String),
+    DnsName(String),
+    Uri(String),
+}
+
+/// Maps an allow-listed client certificate to a client identity, its roles and networks
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityMapping {
+    pub identity: String,
+    pub roles: Roles,
+    pub networks: Vec<IpNet>,
+    pub name: CertificateName,
+}
+
+/// Parses `IDENTITY[:ROLES[:NETWORKS]]=cn|dns|uri:VALUE` with roles like `submit+validate`
+/// and networks like `10.0.0.0/8+2001:db8::/32`
+fn parse_identity_mapping(value: &str) -> Result<IdentityMapping, String> {
+    let Some((identity, name)) = value.split_once('=') else {
+        return Err("expected 'IDENTITY[:ROLES[:NETWORKS]]=cn|dns|uri:VALUE'".to_string());
+    };
+    let mut parts = identity.splitn(3, ':');
+    let identity = parts.next().unwrap_or_default();
+    let (roles, networks) = match (parts.next(), parts.next()) {
+        (Some(roles), networks) if !roles.is_empty() || networks.is_some() => (
+            if roles.is_empty() {
+                Roles::all()
+            } else {
+                Roles(
+                    roles
+                        .split('+')
+                        .map(|role| Role::from_str(role.trim()))
+                        .collect::<Result<Vec<_>, _>>()?,
+                )
+            },
+            networks
+                .map(parse_networks)
+                .transpose()?
+                .unwrap_or_default(),
+        ),
+        (Some(_), _) => return Err("roles must not be empty".to_string()),
+        (None, _) => (Roles::all(), vec![]),
+    };
+    let name = match name.split_once(':') {
+        Some(("cn", cn)) if !cn.is_empty() => CertificateName::CommonName(cn.to_string()),
+        Some(("dns", dns)) if !dns.is_empty() => CertificateName::DnsName(dns.to_string()),
+        Some(("uri", uri)) if !uri.is_empty() => CertificateName::Uri(uri.to_string()),
+        _ => {
+            return Err(
+                "expected certificate name 'cn:VALUE', 'dns:VALUE' or 'uri:VALUE'".to_string(),
+            );
+        }
+    };
+    if identity.is_empty() {
+        return Err("identity must not be empty".to_string());
+    }
+    Ok(IdentityMapping {
+        identity: identity.to_string(),
+        roles,
+        networks,
+        name,
+    })
+}
+
+/// Networks allowed for a client identity from bearer tokens
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityNetworks {
+    pub identity: String,
+    pub networks: Vec<IpNet>,
+}
+
+/// Parses `IDENTITY=NETWORKS` with networks like `10.0.0.0/8+2001:db8::/32`
+fn parse_identity_networks(value: &str) -> Result<IdentityNetworks, String> {
+    match value.split_once('=') {
+        Some((identity, networks)) if !identity.trim().is_empty() => Ok(IdentityNetworks {
+            identity: identity.trim().to_string(),
+            networks: parse_networks(networks)?,
+        }),
+        _ => Err("expected 'IDENTITY=NETWORKS'".to_string()),
+    }
+}
+
+fn parse_networks(value: &str) -> Result<Vec<IpNet>, String> {
+    value.split('+').map(IpNet::from_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::{Role, Roles};
+    use crate::cli::{
+        CertificateName, IdentityMapping, IdentityNetworks, parse_identity_mapping,
+        parse_identity_networks,
+    };
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("mtb-doc=cn:mtb-documentation", CertificateName::CommonName("mtb-documentation".into()))]
+    #[case("mtb-doc=dns:mtb.example.org", CertificateName::DnsName("mtb.example.org".into()))]
+    #[case("mtb-doc=uri:spiffe://example.org/mtb", CertificateName::Uri("spiffe://example.org/mtb".into()))]
+    fn should_parse_identity_mapping(#[case] value: &str, #[case] name: CertificateName) {
+        assert_eq!(
+            parse_identity_mapping(value),
+            Ok(IdentityMapping {
+                identity: "mtb-doc".into(),
+                roles: Roles::all(),
+                networks: vec![],
+                name
+            })
+        );
+    }
+
+    #[rstest]
+    #[case("pathology:submit=cn:pathology", Roles(vec![Role::Submit]))]
+    #[case("mtb-doc:submit+validate=dns:mtb.example.org", Roles(vec![Role::Submit, Role::Validate]))]
+    #[case("mtb-doc:delete=uri:spiffe://example.org/mtb", Roles(vec![Role::Delete]))]
+    fn should_parse_identity_mapping_with_roles(#[case] value: &str, #[case] roles: Roles) {
+        assert_eq!(
+            parse_identity_mapping(value).map(|mapping| mapping.roles),
+            Ok(roles)
+        );
+    }
+
+    #[rstest]
+    #[case("mtb-doc")]
+    #[case("=cn:mtb-documentation")]
+    #[case("mtb-doc=ip:127.0.0.1")]
+    #[case("mtb-doc=cn:")]
+    #[case("mtb-doc:admin=cn:mtb-documentation")]
+    #[case("mtb-doc:=cn:mtb-documentation")]
+    #[case("mtb-doc:submit:=cn:mtb-documentation")]
+    #[case("mtb-doc:submit:10.0.0.0/33=cn:mtb-documentation")]
+    fn should_reject_invalid_identity_mapping(#[case] value: &str) {
+        assert!(parse_identity_mapping(value).is_err());
+    }
+
+    #[rstest]
+    #[case("mtb-doc:delete:10.1.2.0/24=dns:mtb.example.org", Roles(vec![Role::Delete]), &["10.1.2.0/24"])]
+    #[case("mtb-doc::10.1.2.0/24+2001:db8::/32=dns:mtb.example.org", Roles::all(), &["10.1.2.0/24", "2001:db8::/32"])]
+    fn should_parse_identity_mapping_with_networks(
+        #[case] value: &str,
+        #[case] roles: Roles,
+        #[case] networks: &[&str],
+    ) {
+        assert_eq!(
+            parse_identity_mapping(value).map(|mapping| (mapping.roles, mapping.networks)),
+            Ok((
+                roles,
+                networks
+                    .iter()
+                    .filter_map(|network| network.parse().ok())
+                    .collect()
+            ))
+        );
+    }
+
+    #[test]
+    fn should_parse_identity_networks() {
+        assert_eq!(
+            parse_identity_networks("mtb-doc=10.1.2.0/24+2001:db8::/32"),
+            Ok(IdentityNetworks {
+                identity: "mtb-doc".into(),
+                networks: ["10.1.2.0/24", "2001:db8::/32"]
+                    .iter()
+                    .filter_map(|network| network.parse().ok())
+                    .collect(),
+            })
+        );
+    }
+
+    #[rstest]
+    #[case("mtb-doc")]
+    #[case("=10.0.0.0/8")]
+    #[case("mtb-doc=")]
+    #[case("mtb-doc=mtb.example.org")]
+    fn should_reject_invalid_identity_networks(#[case] value: &str) {
+        assert!(parse_identity_networks(value).is_err());
+    }
+}
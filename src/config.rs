@@ -0,0 +1,435 @@
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Command, CommandFactory, FromArgMatches};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{Registry, reload};
+
+use crate::access::AccessControl;
+use crate::auth::Users;
+use crate::cli::{Cli, Command as CliCommand};
+
+/// Options applied on SIGHUP without restart
+const RELOADABLE: &[&str] = &[
+    "log_level",
+    "token",
+    "users_file",
+    "allowed_networks",
+    "trusted_proxies",
+    "forwarded_header",
+];
+
+/// Options with secrets, also read from a file given by env vars like `SECURITY_TOKEN_FILE`
+const SECRETS: &[&str] = &[
+    "token",
+    "ssl_key_password",
+    "sasl_password",
+    "oauth_client_secret",
+];
+
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Parsed configuration and the raw values of all options to detect changes on reload
+pub struct Config {
+    pub cli: Cli,
+    values: BTreeMap<String, Vec<OsString>>,
+}
+
+impl Config {
+    /// Parses options and environment variables layered over the configuration file.
+    /// Secrets read from files must not be given otherwise.
+    pub fn parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        Self::parse_with_env(args, |name| std::env::var_os(name))
+    }
+
+    fn parse_with_env<I, T>(
+        args: I,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let args = args.into_iter().map(Into::into).collect::<Vec<OsString>>();
+        // Options can also be given after a command like `serve`
+        let mut command = Cli::command().mut_args(|arg| arg.global(true));
+        let mut configured = Vec::new();
+
+        let config_file = command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(&args)
+            .ok()
+            .and_then(|matches| matches.get_one::<PathBuf>("config").cloned());
+        if let Some(config_file) = config_file {
+            let entries = read_file(&config_file).map_err(|err| {
+                command.error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "Invalid configuration file '{}': {err}",
+                        config_file.display()
+                    ),
+                )
+            })?;
+            for (id, values) in entries {
+                configured.push(id.clone());
+                command = command.mut_arg(id, |arg| arg.default_values(values));
+            }
+        }
+
+        let secrets = read_secret_files(&command, var)
+            .map_err(|err| command.error(ErrorKind::ArgumentConflict, err))?;
+        for (id, file_env, secret) in &secrets {
+            if configured.contains(id) {
+                return Err(command.error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "'{}' in configuration file and '{file_env}' must not both be set",
+                        id.replace('_', "-")
+                    ),
+                ));
+            }
+            command = command.mut_arg(id, |arg| arg.default_value(secret));
+        }
+
+        let matches = command.try_get_matches_from_mut(&args)?;
+        for (id, file_env, _) in &secrets {
+            if matches.value_source(id) == Some(ValueSource::CommandLine) {
+                return Err(command.error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "'--{}' and '{file_env}' must not both be set",
+                        id.replace('_', "-")
+                    ),
+                ));
+            }
+        }
+        let cli = Cli::from_arg_matches(&matches)?;
+        let values = command
+            .get_arguments()
+            .map(|arg| {
+                let id = arg.get_id().as_str();
+                let values = matches
+                    .try_get_raw(id)
+                    .ok()
+                    .flatten()
+                    .map(|values| values.map(ToOwned::to_owned).collect())
+                    .unwrap_or_default();
+                (id.to_string(), values)
+            })
+            .collect();
+        Ok(Self { cli, values })
+    }
+
+    /// Options changed compared to the previous configuration
+    fn changed<'a>(&'a self, previous: &'a Self) -> impl Iterator<Item = &'a str> {
+        self.values
+            .iter()
+            .filter(|(id, values)| previous.values.get(*id) != Some(values))
+            .map(|(id, _)| id.as_str())
+    }
+}
+
+/// Reads a flat TOML file. Keys are option names like `bootstrap-server` or `allowed-network`.
+fn read_file(path: &Path) -> Result<Vec<(String, Vec<String>)>, String> {
+    let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let table = content
+        .parse::<toml::Table>()
+        .map_err(|err| err.message().to_string())?;
+
+    let command = Cli::command();
+    let mut entries = Vec::new();
+    for (key, value) in table {
+        let Some(id) = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(&key) || arg.get_id() == key.replace('-', "_").as_str()
+            })
+            .map(|arg| arg.get_id().to_string())
+            .filter(|id| id != "config")
+        else {
+            return Err(format!("unknown option '{key}'"));
+        };
+        let values = match value {
+            toml::Value::Array(values) => values
+                .into_iter()
+                .map(|value| toml_value(&key, value))
+                .collect::<Result<Vec<_>, _>>()?,
+            value => vec![toml_value(&key, value)?],
+        };
+        entries.push((id, values));
+    }
+    Ok(entries)
+}
+
+/// Reads secrets from files given by env vars like `SECURITY_TOKEN_FILE` and returns
+/// option ids, env vars and secrets. Trailing line breaks are trimmed.
+fn read_secret_files(
+    command: &Command,
+    var: impl Fn(&str) -> Option<OsString>,
+) -> Result<Vec<(String, String, String)>, String> {
+    let mut secrets = Vec::new();
+    for arg in command
+        .get_arguments()
+        .filter(|arg| SECRETS.contains(&arg.get_id().as_str()))
+    {
+        let Some(env) = arg.get_env().and_then(OsStr::to_str) else {
+            continue;
+        };
+        let file_env = format!("{env}_FILE");
+        let Some(path) = var(&file_env) else {
+            continue;
+        };
+        if var(env).is_some() {
+            return Err(format!("'{env}' and '{file_env}' must not both be set"));
+        }
+        let secret = fs::read_to_string(&path).map_err(|err| {
+            format!(
+                "Cannot read '{file_env}' file '{}': {err}",
+                Path::new(&path).display()
+            )
+        })?;
+        secrets.push((
+            arg.get_id().to_string(),
+            file_env,
+            secret.trim_end_matches(['\r', '\n']).to_string(),
+        ));
+    }
+    Ok(secrets)
+}
+
+fn toml_value(key: &str, value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!("unsupported value of option '{key}'")),
+    }
+}
+
+/// Log level if not configured: detailed for debug builds, no logs for commands other than `serve`
+pub fn log_level(cli: &Cli) -> LevelFilter {
+    match (cli.log_level, &cli.command) {
+        (Some(level), _) => LevelFilter::from_level(level),
+        (None, Some(command)) if *command != CliCommand::Serve => LevelFilter::OFF,
+        (None, _) if cfg!(debug_assertions) => LevelFilter::TRACE,
+        (None, _) => LevelFilter::INFO,
+    }
+}
+
+/// Components updated if the configuration is reloaded
+pub struct Reloadable {
+    pub log_level: LogLevelHandle,
+    pub users: Arc<Users>,
+    pub access: Arc<AccessControl>,
+}
+
+impl Reloadable {
+    /// Applies reloadable options and reports changed options requiring a restart
+    fn apply(&self, config: &Config, previous: &Config) {
+        for id in config
+            .changed(previous)
+            .filter(|id| !RELOADABLE.contains(id))
+        {
+            log::warn!("Changed option '{id}' requires a restart to take effect");
+        }
+
+        if let Err(err) = self.log_level.reload(log_level(&config.cli)) {
+            log::error!("Cannot change log level: {err}");
+        }
+        self.users.reload_config(&config.cli);
+        self.access.reload_config(&config.cli);
+        log::info!("Reloaded configuration");
+    }
+}
+
+/// Reloads configuration file and users files on SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup(reloadable: Reloadable) {
+    let (Ok(mut hangup), Ok(mut current)) = (
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()),
+        Config::parse_from(std::env::args_os()),
+    ) else {
+        log::error!("Cannot install SIGHUP handler, configuration will not be reloaded");
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading configuration");
+        match Config::parse_from(std::env::args_os()) {
+            Ok(config) => {
+                reloadable.apply(&config, &current);
+                current = config;
+            }
+            Err(err) => {
+                log::error!("Cannot reload configuration, keeping current configuration: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn reload_on_hangup(_: Reloadable) {}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Cli;
+    use crate::config::{Config, read_secret_files};
+    use crate::test_util::TempDir;
+    use clap::CommandFactory;
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    fn parse(file: &Path, args: &[&str]) -> Result<Config, clap::Error> {
+        let mut all_args = vec![
+            "gateway".into(),
+            "--config".into(),
+            file.as_os_str().to_owned(),
+        ];
+        all_args.extend(args.iter().map(Into::into));
+        Config::parse_from(all_args)
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_layer_options_over_config_file() {
+        let dir = TempDir::new();
+        let file = dir.file(
+            "config.toml",
+            r#"
+            bootstrap-server = "kafka:9092"
+            topic = "file-topic"
+            allowed-network = ["10.0.0.0/8", "192.168.1.10"]
+            create-topic = true
+            topic-partitions = 3
+            "#,
+        );
+
+        let config = parse(&file, &["--topic", "cli-topic"]).expect("configuration parsed");
+
+        assert_eq!(config.cli.bootstrap_server, "kafka:9092");
+        assert_eq!(config.cli.topic, "cli-topic");
+        assert_eq!(config.cli.allowed_networks.len(), 2);
+        assert!(config.cli.create_topic);
+        assert_eq!(config.cli.topic_partitions, 3);
+    }
+
+    #[test]
+    fn should_reject_invalid_config_file() {
+        let dir = TempDir::new();
+        for content in [
+            "kafka-servers = \"kafka:9092\"",
+            "unknown = 1",
+            "topic-partitions = \"many\"",
+            "[kafka]\ntopic = \"topic\"",
+        ] {
+            assert!(parse(&dir.file("config.toml", content), &[]).is_err());
+        }
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_detect_changed_options() {
+        let dir = TempDir::new();
+        let file = dir.file(
+            "config.toml",
+            "topic = \"file-topic\"\nlog-level = \"info\"",
+        );
+        let previous = parse(&file, &[]).expect("configuration parsed");
+        dir.file(
+            "config.toml",
+            "topic = \"other-topic\"\nlog-level = \"debug\"",
+        );
+        let config = parse(&file, &[]).expect("configuration parsed");
+
+        assert_eq!(
+            config.changed(&previous).collect::<Vec<_>>(),
+            vec!["log_level", "topic"]
+        );
+    }
+
+    #[test]
+    fn should_not_show_secrets_in_help() {
+        let dir = TempDir::new();
+        let token = dir.file("token", "very-secret-token");
+        let password = dir.file("password", "very-secret-password");
+        let var = |name: &str| match name {
+            "SECURITY_TOKEN_FILE" => Some(token.clone().into_os_string()),
+            "KAFKA_SASL_PASSWORD_FILE" => Some(password.clone().into_os_string()),
+            _ => None,
+        };
+
+        let help = Config::parse_with_env(["gateway", "--help"], var)
+            .err()
+            .map(|err| err.to_string())
+            .unwrap_or_default();
+
+        assert!(help.contains("--sasl-password"));
+        assert!(!help.contains("very-secret"));
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_read_secret_files() {
+        let dir = TempDir::new();
+        let token = dir.file(
+            "token",
+            "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG\r\n",
+        );
+        let password = dir.file("password", "very-secret\n\n");
+        let var = |name: &str| match name {
+            "SECURITY_TOKEN_FILE" => Some(token.clone().into_os_string()),
+            "KAFKA_SASL_PASSWORD_FILE" => Some(password.clone().into_os_string()),
+            _ => None,
+        };
+
+        let secrets = read_secret_files(&Cli::command(), var).expect("secrets read");
+
+        assert_eq!(
+            secrets,
+            vec![
+                (
+                    "token".to_string(),
+                    "SECURITY_TOKEN_FILE".to_string(),
+                    "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG".to_string()
+                ),
+                (
+                    "sasl_password".to_string(),
+                    "KAFKA_SASL_PASSWORD_FILE".to_string(),
+                    "very-secret".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_secret_given_as_file_and_env_var() {
+        let dir = TempDir::new();
+        let password = dir.file("password", "very-secret");
+        let var = |name: &str| match name {
+            "KAFKA_SSL_KEY_PASSWORD_FILE" => Some(password.clone().into_os_string()),
+            "KAFKA_SSL_KEY_PASSWORD" => Some(OsString::from("very-secret")),
+            _ => None,
+        };
+
+        assert!(read_secret_files(&Cli::command(), var).is_err());
+    }
+
+    #[test]
+    fn should_reject_unreadable_secret_file() {
+        let var = |name: &str| match name {
+            "KAFKA_OAUTH_CLIENT_SECRET_FILE" => Some(OsString::from("/nonexistent/secret")),
+            _ => None,
+        };
+
+        assert!(read_secret_files(&Cli::command(), var).is_err());
+    }
+}
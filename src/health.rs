@@ -0,0 +1,341 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+use crate::spool::Spool;
+
+pub type DynHealth = Arc<Health>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Status {
+    Up,
+    Down,
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Component {
+    pub status: Status,
+    pub details: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: Status,
+    pub components: BTreeMap<&'static str, Component>,
+}
+
+#[derive(Clone, Copy)]
+struct KafkaStats {
+    brokers: usize,
+    brokers_up: usize,
+    queued_messages: u64,
+}
+
+/// Current state of all components needed to accept records
+#[derive(Default)]
+pub struct Health {
+    kafka: RwLock<Option<KafkaStats>>,
+    topic: RwLock<Option<Result<(), String>>>,
+    oauth: RwLock<Option<Result<(), String>>>,
+    spool: RwLock<Option<Arc<Spool>>>,
+}
+
+impl Health {
+    /// Updates broker state as reported by librdkafka statistics
+    pub fn update_kafka_stats(&self, brokers: usize, brokers_up: usize, queued_messages: u64) {
+        if let Ok(mut kafka) = self.kafka.write() {
+            *kafka = Some(KafkaStats {
+                brokers,
+                brokers_up,
+                queued_messages,
+            });
+        }
+    }
+
+    pub fn set_topic_check(&self, result: Result<(), String>) {
+        if let Ok(mut topic) = self.topic.write() {
+            *topic = Some(result);
+        }
+    }
+
+    pub fn set_oauth_token_fetch(&self, result: Result<(), String>) {
+        if let Ok(mut oauth) = self.oauth.write() {
+            *oauth = Some(result);
+        }
+    }
+
+    pub fn set_spool(&self, spool: Arc<Spool>) {
+        if let Ok(mut current) = self.spool.write() {
+            *current = Some(spool);
+        }
+    }
+
+    /// Records can be accepted if the topic is usable and Kafka is connected
+    /// or records can be spooled.
+    pub fn readiness(&self) -> Readiness {
+        let mut components = BTreeMap::new();
+
+        let kafka = self.kafka.read().ok().and_then(|kafka| *kafka);
+        let kafka_status = match kafka {
+            Some(stats) if stats.brokers_up > 0 => Status::Up,
+            Some(_) => Status::Down,
+            None => Status::Unknown,
+        };
+        components.insert(
+            "kafka",
+            Component {
+                status: kafka_status,
+                details: kafka.map_or(json!({}), |stats| {
+                    json!({
+                        "brokers": stats.brokers,
+                        "brokersUp": stats.brokers_up,
+                        "queuedMessages": stats.queued_messages,
+                    })
+                }),
+            },
+        );
+
+        let topic_status = match self.topic.read().ok().as_deref() {
+            Some(Some(Ok(()))) => Some(Component {
+                status: Status::Up,
+                details: json!({}),
+            }),
+            Some(Some(Err(err))) => Some(Component {
+                status: Status::Down,
+                details: json!({ "error": err }),
+            }),
+            _ => None,
+        };
+        if let Some(component) = topic_status {
+            components.insert("topic", component);
+        }
+
+        let oauth_status = match self.oauth.read().ok().as_deref() {
+            Some(Some(Ok(()))) => Some(Component {
+                status: Status::Up,
+                details: json!({}),
+            }),
+            Some(Some(Err(err))) => Some(Component {
+                status: Status::Down,
+                details: json!({ "error": err }),
+            }),
+            _ => None,
+        };
+        if let Some(component) = oauth_status {
+            components.insert("oauth", component);
+        }
+
+        let spool = self.spool.read().ok().and_then(|spool| spool.clone());
+        if let Some(spool) = spool {
+            components.insert(
+                "spool",
+                Component {
+                    status: if spool.is_full() {
+                        Status::Down
+                    } else {
+                        Status::Up
+                    },
+                    details: json!({
+                        "records": spool.len(),
+                        "maxRecords": spool.max_records(),
+                        "size": spool.size(),
+                        "maxSize": spool.max_size(),
+                    }),
+                },
+            );
+        }
+
+        let is_up = |name| {
+            components
+                .get(name)
+                .map(|component: &Component| component.status)
+        };
+        let can_send = kafka_status == Status::Up || is_up("spool") == Some(Status::Up);
+        let status = if can_send
+            && is_up("topic") != Some(Status::Down)
+            && is_up("oauth") != Some(Status::Down)
+        {
+            Status::Up
+        } else {
+            Status::Down
+        };
+
+        Readiness { status, components }
+    }
+}
+
+async fn handle_live() -> Response {
+    Json(json!({ "status": Status::Up })).into_response()
+}
+
+async fn handle_ready(Extension(health): Extension<DynHealth>) -> Response {
+    let readiness = health.readiness();
+    let status = match readiness.status {
+        Status::Up => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness)).into_response()
+}
+
+/// Health routes do not require authentication
+pub fn routes(health: DynHealth) -> Router {
+    Router::new()
+        .route("/health/live", get(handle_live))
+        .route("/health/ready", get(handle_ready))
+        .layer(Extension(health))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::{Health, Status, routes};
+    use crate::sender::RequestMethod;
+    use crate::spool::{Spool, SpooledRecord};
+    use crate::test_util::TempDir;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[test]
+    fn should_be_unknown_without_kafka_stats() {
+        let health = Health::default();
+
+        let readiness = health.readiness();
+
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.components["kafka"].status, Status::Unknown);
+    }
+
+    #[test]
+    fn should_be_ready_with_connected_broker() {
+        let health = Health::default();
+        health.update_kafka_stats(3, 1, 0);
+        health.set_topic_check(Ok(()));
+
+        let readiness = health.readiness();
+
+        assert_eq!(readiness.status, Status::Up);
+        assert_eq!(readiness.components["kafka"].status, Status::Up);
+        assert_eq!(readiness.components["kafka"].details["brokersUp"], 1);
+    }
+
+    #[test]
+    fn should_not_be_ready_without_connected_broker() {
+        let health = Health::default();
+        health.update_kafka_stats(3, 0, 0);
+
+        assert_eq!(health.readiness().status, Status::Down);
+    }
+
+    #[test]
+    fn should_not_be_ready_if_topic_check_failed() {
+        let health = Health::default();
+        health.update_kafka_stats(1, 1, 0);
+        health.set_topic_check(Err("Kafka topic 'test' does not exist".to_string()));
+
+        let readiness = health.readiness();
+
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.components["topic"].status, Status::Down);
+    }
+
+    #[test]
+    fn should_not_be_ready_if_oauth_token_fetch_failed() {
+        let health = Health::default();
+        health.update_kafka_stats(1, 1, 0);
+        health.set_oauth_token_fetch(Err("Token endpoint not available".to_string()));
+
+        assert_eq!(health.readiness().status, Status::Down);
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_be_ready_without_connected_broker_if_records_can_be_spooled() {
+        let dir = TempDir::new();
+        let spool = Arc::new(Spool::open(dir.path(), 1, 1024 * 1024).expect("spool opened"));
+
+        let health = Health::default();
+        health.update_kafka_stats(1, 0, 0);
+        health.set_spool(Arc::clone(&spool));
+        assert_eq!(health.readiness().status, Status::Up);
+
+        spool
+            .push(&SpooledRecord {
+                request_id: "test".to_string(),
+                request_method: RequestMethod::Post,
+                key: "{}".to_string(),
+                payload: "{}".to_string(),
+                client_identity: None,
+            })
+            .expect("record spooled");
+
+        let readiness = health.readiness();
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.components["spool"].status, Status::Down);
+        assert_eq!(readiness.components["spool"].details["records"], 1);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_respond_to_liveness_probe_without_authentication() {
+        let router = routes(Arc::new(Health::default()));
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/health/live")
+                    .body(Body::empty())
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_respond_to_readiness_probe() {
+        let health = Arc::new(Health::default());
+        let router = routes(Arc::clone(&health));
+
+        let response = router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/health/ready")
+                    .body(Body::empty())
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        health.update_kafka_stats(1, 1, 0);
+
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/health/ready")
+                    .body(Body::empty())
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body read");
+        let body = serde_json::from_slice::<serde_json::Value>(&body).expect("JSON body");
+        assert_eq!(body["status"], "UP");
+        assert_eq!(body["components"]["kafka"]["details"]["brokers"], 1);
+    }
+}
@@ -0,0 +1,535 @@
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use ring::signature::{
+    ECDSA_P256_SHA256_FIXED, ECDSA_P384_SHA384_FIXED, RSA_PKCS1_2048_8192_SHA256,
+    RSA_PKCS1_2048_8192_SHA384, RSA_PKCS1_2048_8192_SHA512, RSA_PSS_2048_8192_SHA256,
+    RSA_PSS_2048_8192_SHA384, RSA_PSS_2048_8192_SHA512, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::auth::{ClientIdentity, Role, Roles};
+use crate::cli::Cli;
+
+/// Interval to reload the JWKS to pick up rotated keys
+const RELOAD_INTERVAL: Duration = Duration::from_mins(5);
+
+/// Minimum interval between reloads triggered by tokens with an unknown key id
+const UNKNOWN_KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Accepted clock skew for 'exp' and 'nbf' claims
+const LEEWAY_SECONDS: u64 = 60;
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// Public key of a JWKS as described in RFC 7517
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl Jwk {
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        if self.alg.as_deref().is_some_and(|key_alg| key_alg != alg)
+            || self.usage.as_deref().is_some_and(|usage| usage != "sig")
+        {
+            return false;
+        }
+
+        match (self.kty.as_str(), alg) {
+            ("RSA", _) => {
+                let params = match alg {
+                    "RS256" => &RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &RSA_PKCS1_2048_8192_SHA512,
+                    "PS256" => &RSA_PSS_2048_8192_SHA256,
+                    "PS384" => &RSA_PSS_2048_8192_SHA384,
+                    "PS512" => &RSA_PSS_2048_8192_SHA512,
+                    _ => return false,
+                };
+                let (Some(n), Some(e)) = (decode(self.n.as_deref()), decode(self.e.as_deref()))
+                else {
+                    return false;
+                };
+                RsaPublicKeyComponents { n, e }
+                    .verify(params, message, signature)
+                    .is_ok()
+            }
+            ("EC", "ES256" | "ES384") => {
+                let params = match (alg, self.crv.as_deref()) {
+                    ("ES256", Some("P-256")) => &ECDSA_P256_SHA256_FIXED,
+                    ("ES384", Some("P-384")) => &ECDSA_P384_SHA384_FIXED,
+                    _ => return false,
+                };
+                let (Some(x), Some(y)) = (decode(self.x.as_deref()), decode(self.y.as_deref()))
+                else {
+                    return false;
+                };
+                // Uncompressed point
+                let mut point = vec![0x04];
+                point.extend(x);
+                point.extend(y);
+                UnparsedPublicKey::new(params, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+fn decode(value: Option<&str>) -> Option<Vec<u8>> {
+    BASE64_URL_SAFE_NO_PAD.decode(value?).ok()
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Returns the claim for a name like `sub` or a path like `realm_access.roles`
+fn claim<'a>(claims: &'a Value, name: &str) -> Option<&'a Value> {
+    name.split('.')
+        .try_fold(claims, |value, segment| value.get(segment))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+pub type DynJwtValidator = Arc<JwtValidator>;
+
+/// Validates bearer tokens issued by an OIDC identity provider
+pub struct JwtValidator {
+    jwks: String,
+    issuer: String,
+    audience: String,
+    identity_claim: String,
+    roles_claim: Option<String>,
+    keys: RwLock<Vec<Jwk>>,
+    reloaded_at: Mutex<Option<Instant>>,
+    agent: ureq::Agent,
+}
+
+impl JwtValidator {
+    /// Returns the validator if a JWKS is configured. Fails if the JWKS cannot be loaded.
+    pub fn new(cli: &Cli) -> Result<Option<Self>, String> {
+        let Some(jwks) = &cli.jwt_jwks else {
+            return Ok(None);
+        };
+        let (Some(issuer), Some(audience)) = (&cli.jwt_issuer, &cli.jwt_audience) else {
+            return Err("JWT authentication requires issuer and audience".to_string());
+        };
+
+        let validator = Self {
+            jwks: jwks.clone(),
+            issuer: issuer.clone(),
+            audience: audience.clone(),
+            identity_claim: cli.jwt_identity_claim.clone(),
+            roles_claim: cli.jwt_roles_claim.clone(),
+            keys: RwLock::default(),
+            reloaded_at: Mutex::default(),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(10)))
+                .build()
+                .new_agent(),
+        };
+        validator.reload()?;
+        Ok(Some(validator))
+    }
+
+    /// Loads the JWKS from an URL or a local file
+    fn fetch_keys(&self) -> Result<Vec<Jwk>, String> {
+        let jwk_set = if self.jwks.starts_with("https://") || self.jwks.starts_with("http://") {
+            self.agent
+                .get(&self.jwks)
+                .call()
+                .and_then(|mut response| response.body_mut().read_json::<JwkSet>())
+                .map_err(|err| format!("Cannot fetch JWKS from '{}': {err}", self.jwks))?
+        } else {
+            let content = std::fs::read_to_string(&self.jwks)
+                .map_err(|err| format!("Cannot read JWKS file '{}': {err}", self.jwks))?;
+            serde_json::from_str::<JwkSet>(&content)
+                .map_err(|err| format!("Invalid JWKS file '{}': {err}", self.jwks))?
+        };
+        Ok(jwk_set.keys)
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let keys = self.fetch_keys()?;
+        if let Ok(mut current) = self.keys.write() {
+            *current = keys;
+        }
+        if let Ok(mut reloaded_at) = self.reloaded_at.lock() {
+            *reloaded_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn has_key(&self, kid: &str) -> bool {
+        self.keys
+            .read()
+            .is_ok_and(|keys| keys.iter().any(|key| key.kid.as_deref() == Some(kid)))
+    }
+
+    /// Reloads the JWKS for a token signed with a key rotated in since the last reload.
+    /// Concurrent requests wait for the running reload instead of fetching the JWKS again.
+    fn reload_for_unknown_key(&self, kid: &str) {
+        let Ok(mut reloaded_at) = self.reloaded_at.lock() else {
+            return;
+        };
+        if self.has_key(kid)
+            || reloaded_at
+                .is_some_and(|reloaded_at| reloaded_at.elapsed() < UNKNOWN_KEY_RELOAD_INTERVAL)
+        {
+            return;
+        }
+
+        log::info!("Reloading JWKS for unknown key id '{kid}'");
+        *reloaded_at = Some(Instant::now());
+        match self.fetch_keys() {
+            Ok(keys) => {
+                if let Ok(mut current) = self.keys.write() {
+                    *current = keys;
+                }
+            }
+            Err(err) => log::error!("Cannot reload JWKS, keeping current keys: {err}"),
+        }
+    }
+
+    /// Returns identity and roles if signature, issuer, audience and expiry are valid
+    pub fn validate(&self, token: &str) -> Result<(ClientIdentity, Roles), String> {
+        let Some((message, signature)) = token.rsplit_once('.') else {
+            return Err("Malformed token".to_string());
+        };
+        let Some((header, payload)) = message.split_once('.') else {
+            return Err("Malformed token".to_string());
+        };
+
+        let header = decode(Some(header))
+            .and_then(|header| serde_json::from_slice::<Header>(&header).ok())
+            .ok_or("Malformed token header")?;
+        let claims = decode(Some(payload))
+            .and_then(|payload| serde_json::from_slice::<Value>(&payload).ok())
+            .ok_or("Malformed token payload")?;
+        let signature = decode(Some(signature)).ok_or("Malformed token signature")?;
+
+        if let Some(kid) = &header.kid
+            && !self.has_key(kid)
+        {
+            self.reload_for_unknown_key(kid);
+        }
+
+        let verified = self.keys.read().is_ok_and(|keys| {
+            keys.iter()
+                .filter(|key| header.kid.is_none() || key.kid == header.kid)
+                .any(|key| key.verify(&header.alg, message.as_bytes(), &signature))
+        });
+        if !verified {
+            return Err(format!(
+                "No matching key for algorithm '{}' and key id '{}'",
+                header.alg,
+                header.kid.unwrap_or_default()
+            ));
+        }
+
+        self.validate_claims(&claims)
+    }
+
+    fn validate_claims(&self, claims: &Value) -> Result<(ClientIdentity, Roles), String> {
+        if claims.get("iss").and_then(Value::as_str) != Some(self.issuer.as_str()) {
+            return Err("Unexpected issuer".to_string());
+        }
+
+        let audience_matches = match claims.get("aud") {
+            Some(Value::String(aud)) => aud == &self.audience,
+            Some(Value::Array(aud)) => aud
+                .iter()
+                .any(|aud| aud.as_str() == Some(self.audience.as_str())),
+            _ => false,
+        };
+        if !audience_matches {
+            return Err("Unexpected audience".to_string());
+        }
+
+        let now = now();
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) if exp.saturating_add(LEEWAY_SECONDS) >= now => {}
+            Some(_) => return Err("Token expired".to_string()),
+            None => return Err("Token without expiry".to_string()),
+        }
+        if claims
+            .get("nbf")
+            .and_then(Value::as_u64)
+            .is_some_and(|nbf| nbf > now + LEEWAY_SECONDS)
+        {
+            return Err("Token not yet valid".to_string());
+        }
+
+        let identity = claim(claims, &self.identity_claim)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("Token without claim '{}'", self.identity_claim))?;
+
+        let roles = match &self.roles_claim {
+            Some(roles_claim) => {
+                let names = match claim(claims, roles_claim) {
+                    Some(Value::Array(roles)) => roles.iter().filter_map(Value::as_str).collect(),
+                    // Space separated values like 'scope'
+                    Some(Value::String(roles)) => roles.split(' ').collect(),
+                    _ => vec![],
+                };
+                Roles(
+                    names
+                        .into_iter()
+                        .filter_map(|name| Role::from_str(name).ok())
+                        .collect(),
+                )
+            }
+            None => Roles(vec![]),
+        };
+
+        Ok((ClientIdentity(identity.to_string()), roles))
+    }
+}
+
+pub async fn reload_periodically(validator: Weak<JwtValidator>) {
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        match validator.upgrade() {
+            Some(validator) => {
+                let result = tokio::task::spawn_blocking(move || validator.reload()).await;
+                if let Ok(Err(err)) = result {
+                    log::error!("Cannot reload JWKS, keeping current keys: {err}");
+                }
+            }
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::auth::{ClientIdentity, Role, Roles};
+    use crate::jwt::{JwtValidator, now};
+    use base64::Engine;
+    use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+    use ring::rand::SystemRandom;
+    use ring::signature::{
+        ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, RSA_PKCS1_SHA256, RsaKeyPair,
+    };
+    use rstest::rstest;
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::pki_types::pem::PemObject;
+    use serde_json::{Value, json};
+    use std::sync::{Mutex, RwLock};
+    use std::time::Instant;
+
+    const TEST_FILES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-files/jwt");
+
+    pub(crate) const ISSUER: &str = "https://idp.example.org/realms/dnpm";
+    pub(crate) const AUDIENCE: &str = "mv64e-gateway";
+
+    #[allow(clippy::expect_used)]
+    fn private_key(name: &str) -> Vec<u8> {
+        PrivateKeyDer::from_pem_file(format!("{TEST_FILES}/{name}"))
+            .expect("key read")
+            .secret_der()
+            .to_vec()
+    }
+
+    #[allow(clippy::expect_used)]
+    pub(crate) fn validator(roles_claim: Option<&str>) -> JwtValidator {
+        let validator = JwtValidator {
+            jwks: format!("{TEST_FILES}/jwks.json"),
+            issuer: ISSUER.to_string(),
+            audience: AUDIENCE.to_string(),
+            identity_claim: "azp".to_string(),
+            roles_claim: roles_claim.map(ToString::to_string),
+            keys: RwLock::default(),
+            reloaded_at: Mutex::default(),
+            agent: ureq::Agent::new_with_defaults(),
+        };
+        validator.reload().expect("JWKS loaded");
+        validator
+    }
+
+    pub(crate) fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": ["account", AUDIENCE],
+            "azp": "mtb-doc",
+            "exp": now() + 300,
+            "realm_access": { "roles": ["submit", "offline_access"] },
+            "scope": "profile validate delete",
+        })
+    }
+
+    /// Signs claims with the RSA test key
+    #[allow(clippy::expect_used)]
+    pub(crate) fn rs256_token(kid: &str, claims: &Value) -> String {
+        let message = message("RS256", kid, claims);
+        let key = RsaKeyPair::from_pkcs8(&private_key("rsa.key")).expect("RSA key");
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .expect("token signed");
+        format!("{message}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Signs claims with the EC test key
+    #[allow(clippy::expect_used)]
+    fn es256_token(claims: &Value) -> String {
+        let message = message("ES256", "ec-test", claims);
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &private_key("ec.key"),
+            &rng,
+        )
+        .expect("EC key");
+        let signature = key.sign(&rng, message.as_bytes()).expect("token signed");
+        format!("{message}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn message(alg: &str, kid: &str, claims: &Value) -> String {
+        let header = json!({ "typ": "JWT", "alg": alg, "kid": kid });
+        format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+            BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    #[test]
+    fn should_accept_valid_rs256_token() {
+        let token = rs256_token("rsa-test", &claims());
+
+        assert_eq!(
+            validator(None).validate(&token),
+            Ok((ClientIdentity("mtb-doc".to_string()), Roles(vec![])))
+        );
+    }
+
+    #[test]
+    fn should_accept_valid_es256_token() {
+        let token = es256_token(&claims());
+
+        assert!(validator(None).validate(&token).is_ok());
+    }
+
+    #[rstest]
+    #[case("realm_access.roles", Roles(vec![Role::Submit]))]
+    #[case("scope", Roles(vec![Role::Validate, Role::Delete]))]
+    #[case("groups", Roles(vec![]))]
+    fn should_map_roles_claim(#[case] roles_claim: &str, #[case] expected: Roles) {
+        let token = rs256_token("rsa-test", &claims());
+
+        assert_eq!(
+            validator(Some(roles_claim))
+                .validate(&token)
+                .map(|(_, roles)| roles),
+            Ok(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("iss", json!("https://other.example.org"))]
+    #[case("aud", json!("account"))]
+    #[case("exp", json!(now() - 120))]
+    #[case("nbf", json!(now() + 120))]
+    #[case("azp", Value::Null)]
+    fn should_reject_token_with_invalid_claim(#[case] name: &str, #[case] value: Value) {
+        let mut claims = claims();
+        claims[name] = value;
+        let token = rs256_token("rsa-test", &claims);
+
+        assert!(validator(None).validate(&token).is_err());
+    }
+
+    #[test]
+    fn should_reject_token_with_unknown_key_id() {
+        let token = rs256_token("other", &claims());
+
+        assert!(validator(None).validate(&token).is_err());
+    }
+
+    #[test]
+    fn should_reload_keys_for_unknown_key_id() {
+        let validator = validator(None);
+        validator
+            .keys
+            .write()
+            .iter_mut()
+            .for_each(|keys| keys.clear());
+        validator
+            .reloaded_at
+            .lock()
+            .iter_mut()
+            .for_each(|reloaded_at| **reloaded_at = None);
+        let token = rs256_token("rsa-test", &claims());
+
+        assert!(validator.validate(&token).is_ok());
+    }
+
+    #[test]
+    fn should_limit_reloads_for_unknown_key_id() {
+        let validator = validator(None);
+        validator
+            .keys
+            .write()
+            .iter_mut()
+            .for_each(|keys| keys.clear());
+        validator
+            .reloaded_at
+            .lock()
+            .iter_mut()
+            .for_each(|reloaded_at| **reloaded_at = Some(Instant::now()));
+        let token = rs256_token("rsa-test", &claims());
+
+        assert!(validator.validate(&token).is_err());
+    }
+
+    #[test]
+    fn should_reject_tampered_token() {
+        let token = rs256_token("rsa-test", &claims());
+        let mut parts = token
+            .split('.')
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let mut claims = claims();
+        claims["azp"] = json!("other");
+        parts[1] = BASE64_URL_SAFE_NO_PAD.encode(claims.to_string());
+
+        assert!(validator(None).validate(&parts.join(".")).is_err());
+    }
+
+    #[test]
+    fn should_reject_unsigned_token() {
+        let token = format!("{}.", message("none", "rsa-test", &claims()));
+
+        assert!(validator(None).validate(&token).is_err());
+    }
+}
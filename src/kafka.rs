@@ -0,0 +1,936 @@
+use rdkafka::ClientConfig;
+use rdkafka::admin::{AdminClient, AdminOptions, NewTopic, TopicReplication};
+use rdkafka::client::{ClientContext, OAuthToken};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, Producer};
+use rdkafka::statistics::Statistics;
+use serde::Deserialize;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cli::{Cli, DeliveryGuarantee, SaslMechanism, SecurityProtocol};
+use crate::health::DynHealth;
+use crate::metrics::METRICS;
+
+/// Prefix of environment variables containing additional librdkafka producer properties
+const PRODUCER_PROPERTY_ENV_PREFIX: &str = "KAFKA_PRODUCER_";
+
+/// Timeout for Kafka metadata and admin requests
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval of librdkafka statistics used for readiness
+const STATISTICS_INTERVAL_MS: &str = "5000";
+
+/// Interval to check the topic again if the startup check failed
+const TOPIC_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Lifetime used if the token endpoint does not return `expires_in`
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_mins(5);
+
+#[allow(clippy::module_name_repetitions)]
+pub type KafkaProducer = FutureProducer<KafkaContext>;
+
+/// Client context used for all Kafka clients of this application
+#[allow(clippy::module_name_repetitions)]
+pub struct KafkaContext {
+    token_provider: Option<OAuthTokenProvider>,
+    health: Option<DynHealth>,
+}
+
+impl KafkaContext {
+    pub fn new(cli: &Cli, health: DynHealth) -> Result<Self, String> {
+        Ok(Self {
+            token_provider: Self::token_provider(cli)?,
+            health: Some(health),
+        })
+    }
+
+    /// Context for short-lived clients that must not report health and metrics
+    /// in place of the producer
+    fn without_health(cli: &Cli) -> Result<Self, String> {
+        Ok(Self {
+            token_provider: Self::token_provider(cli)?,
+            health: None,
+        })
+    }
+
+    fn token_provider(cli: &Cli) -> Result<Option<OAuthTokenProvider>, String> {
+        if cli.sasl_mechanism == SaslMechanism::OAuthBearer {
+            return OAuthTokenProvider::new(cli).map(Some);
+        }
+        Ok(None)
+    }
+}
+
+impl ClientContext for KafkaContext {
+    // librdkafka requests a new token after 80% of the token lifetime
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn Error>> {
+        match &self.token_provider {
+            Some(token_provider) => match token_provider.fetch_token() {
+                Ok(token) => {
+                    if let Some(health) = &self.health {
+                        health.set_oauth_token_fetch(Ok(()));
+                    }
+                    Ok(token)
+                }
+                Err(err) => {
+                    log::error!("Cannot fetch OAuth token: {err}");
+                    if let Some(health) = &self.health {
+                        health.set_oauth_token_fetch(Err(err.clone()));
+                    }
+                    Err(err.into())
+                }
+            },
+            None => Err("No OAuth token endpoint configured".into()),
+        }
+    }
+
+    fn stats(&self, statistics: Statistics) {
+        let Some(health) = &self.health else {
+            return;
+        };
+        let brokers_up = statistics
+            .brokers
+            .values()
+            .filter(|broker| broker.state == "UP")
+            .count();
+        health.update_kafka_stats(statistics.brokers.len(), brokers_up, statistics.msg_cnt);
+        METRICS.producer_queue(statistics.msg_cnt, statistics.msg_size);
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Fetches OAuth tokens using the client credentials grant
+pub struct OAuthTokenProvider {
+    token_endpoint: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl OAuthTokenProvider {
+    pub fn new(cli: &Cli) -> Result<Self, String> {
+        let (Some(token_endpoint), Some(client_id), Some(client_secret)) = (
+            &cli.oauth_token_endpoint,
+            &cli.oauth_client_id,
+            &cli.oauth_client_secret,
+        ) else {
+            return Err(
+                "SASL mechanism 'OAUTHBEARER' requires token endpoint, client id and client secret"
+                    .to_string(),
+            );
+        };
+
+        Ok(Self {
+            token_endpoint: token_endpoint.clone(),
+            client_id: client_id.clone(),
+            client_secret: client_secret.clone(),
+            scope: cli.oauth_scope.clone(),
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(10)))
+                .build()
+                .new_agent(),
+        })
+    }
+
+    pub fn fetch_token(&self) -> Result<OAuthToken, String> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+        ];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope.as_str()));
+        }
+
+        let token_response = self
+            .agent
+            .post(&self.token_endpoint)
+            .send_form(form)
+            .and_then(|mut response| response.body_mut().read_json::<TokenResponse>())
+            .map_err(|err| {
+                format!(
+                    "Token endpoint '{}' did not respond with a token: {err}",
+                    self.token_endpoint
+                )
+            })?;
+
+        let lifetime = token_response
+            .expires_in
+            .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs);
+        let expires_at = SystemTime::now()
+            .checked_add(lifetime)
+            .and_then(|expires_at| expires_at.duration_since(UNIX_EPOCH).ok())
+            .ok_or("Cannot calculate token lifetime")?;
+
+        log::debug!(
+            "Fetched OAuth token for client '{}' valid for {}s",
+            self.client_id,
+            lifetime.as_secs()
+        );
+
+        Ok(OAuthToken {
+            token: token_response.access_token,
+            principal_name: self.client_id.clone(),
+            lifetime_ms: i64::try_from(expires_at.as_millis()).unwrap_or(i64::MAX),
+        })
+    }
+}
+
+pub fn client_config(
+    cli: &Cli,
+    env_vars: impl Iterator<Item = (String, String)>,
+) -> Result<ClientConfig, String> {
+    let mut client_config = ClientConfig::new();
+
+    client_config
+        .set("bootstrap.servers", &cli.bootstrap_server)
+        .set("message.timeout.ms", "5000")
+        // The topic check must not create the topic with broker defaults
+        .set("allow.auto.create.topics", "false")
+        .set("statistics.interval.ms", STATISTICS_INTERVAL_MS);
+
+    let security_protocol = cli.security_protocol.unwrap_or(
+        match (
+            cli.ssl_cert_file.is_some() || cli.ssl_key_file.is_some(),
+            cli.sasl_username.is_some() || cli.sasl_mechanism == SaslMechanism::OAuthBearer,
+        ) {
+            (true, false) => SecurityProtocol::Ssl,
+            (_, true) => SecurityProtocol::SaslSsl,
+            _ => SecurityProtocol::Plaintext,
+        },
+    );
+
+    client_config.set("security.protocol", security_protocol.to_string());
+
+    if security_protocol.uses_ssl() {
+        if let Some(ssl_ca_file) = &cli.ssl_ca_file {
+            client_config.set("ssl.ca.location", ssl_ca_file);
+        }
+        if let Some(ssl_cert_file) = &cli.ssl_cert_file {
+            client_config.set("ssl.certificate.location", ssl_cert_file);
+        }
+        if let Some(ssl_key_file) = &cli.ssl_key_file {
+            client_config.set("ssl.key.location", ssl_key_file);
+        }
+        if let Some(ssl_key_password) = &cli.ssl_key_password {
+            client_config.set("ssl.key.password", ssl_key_password);
+        }
+    }
+
+    if security_protocol.uses_sasl() {
+        client_config.set("sasl.mechanism", cli.sasl_mechanism.to_string());
+
+        // Tokens are provided by KafkaContext
+        if cli.sasl_mechanism != SaslMechanism::OAuthBearer {
+            let (Some(sasl_username), Some(sasl_password)) =
+                (&cli.sasl_username, &cli.sasl_password)
+            else {
+                return Err(format!(
+                    "Security protocol '{security_protocol}' requires SASL username and password"
+                ));
+            };
+            client_config
+                .set("sasl.username", sasl_username)
+                .set("sasl.password", sasl_password);
+        }
+    }
+
+    if cli.delivery_guarantee == DeliveryGuarantee::Idempotent {
+        // Retries will neither duplicate nor reorder records of a partition
+        // and therefore records of the same patient
+        client_config
+            .set("enable.idempotence", "true")
+            .set("acks", "all")
+            .set("max.in.flight.requests.per.connection", "5");
+    }
+
+    for (key, value) in producer_properties(cli, env_vars)? {
+        log::info!(
+            "Using Kafka producer property '{key}' = '{}'",
+            masked_value(&key, &value)
+        );
+        client_config.set(key, value);
+    }
+
+    Ok(client_config)
+}
+
+/// Collects additional producer properties from command line and environment variables
+/// like `KAFKA_PRODUCER_LINGER_MS=10` for property `linger.ms`.
+/// Each property is validated by librdkafka.
+fn producer_properties(
+    cli: &Cli,
+    env_vars: impl Iterator<Item = (String, String)>,
+) -> Result<Vec<(String, String)>, String> {
+    let mut properties = env_vars
+        .filter_map(|(name, value)| {
+            name.strip_prefix(PRODUCER_PROPERTY_ENV_PREFIX)
+                .map(|key| (key.to_lowercase().replace('_', "."), value))
+        })
+        .collect::<Vec<_>>();
+    properties.sort();
+
+    for property in &cli.producer_properties {
+        match property.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                properties.push((key.trim().to_string(), value.to_string()));
+            }
+            _ => {
+                return Err(format!(
+                    "Invalid Kafka producer property '{property}': expected 'KEY=VALUE'"
+                ));
+            }
+        }
+    }
+
+    for (key, value) in &properties {
+        if cli.delivery_guarantee == DeliveryGuarantee::Idempotent
+            && conflicts_with_idempotence(key, value)
+        {
+            return Err(format!(
+                "Kafka producer property '{key}' = '{value}' conflicts with delivery guarantee 'idempotent'"
+            ));
+        }
+        if let Err(err) = ClientConfig::new().set(key, value).create_native_config() {
+            let reason = match err {
+                KafkaError::ClientConfig(_, reason, _, _) => reason,
+                err => err.to_string(),
+            };
+            return Err(format!("Invalid Kafka producer property '{key}': {reason}"));
+        }
+    }
+
+    Ok(properties)
+}
+
+/// Checks if a property would weaken the settings of an idempotent producer.
+/// Values are compared like librdkafka does, e.g. `acks=ALL` or `enable.idempotence=1`.
+fn conflicts_with_idempotence(key: &str, value: &str) -> bool {
+    let value = value.trim().to_lowercase();
+    match key {
+        "enable.idempotence" => !matches!(value.as_str(), "true" | "t" | "1"),
+        "acks" | "request.required.acks" => !matches!(value.as_str(), "all" | "-1"),
+        "max.in.flight.requests.per.connection" | "max.in.flight" => {
+            value.parse::<u32>().map_or(true, |value| value > 5)
+        }
+        "retries" | "message.send.max.retries" => {
+            value.parse::<u32>().map_or(true, |value| value == 0)
+        }
+        _ => false,
+    }
+}
+
+/// Masks values of properties that may contain secrets
+fn masked_value<'a>(key: &str, value: &'a str) -> &'a str {
+    if [
+        "password",
+        "secret",
+        "jaas",
+        "oauthbearer.config",
+        "ssl.key",
+    ]
+    .iter()
+    .any(|part| key.contains(part))
+    {
+        return "********";
+    }
+    value
+}
+
+pub fn create_producer(cli: &Cli, health: DynHealth) -> Result<KafkaProducer, String> {
+    client_config(cli, std::env::vars())?
+        .create_with_context::<_, KafkaProducer>(KafkaContext::new(cli, health)?)
+        .map_err(|err| err.to_string())
+}
+
+/// Verifies that Kafka is reachable and the configured topic exists and is writable.
+/// A missing topic will be created if configured.
+pub async fn verify_topic(
+    cli: &Cli,
+    producer: &KafkaProducer,
+    health: &DynHealth,
+) -> Result<(), String> {
+    let result = check_topic(cli, producer).await;
+    health.set_topic_check(result.clone());
+    result
+}
+
+/// Checks the topic again until it is available
+pub async fn recheck_topic(cli: &Cli, producer: KafkaProducer, health: DynHealth) {
+    loop {
+        tokio::time::sleep(TOPIC_RECHECK_INTERVAL).await;
+        match verify_topic(cli, &producer, &health).await {
+            Ok(()) => return,
+            Err(err) => log::warn!("Kafka topic check failed: {err}"),
+        }
+    }
+}
+
+async fn check_topic(cli: &Cli, producer: &KafkaProducer) -> Result<(), String> {
+    let topic = cli.topic.clone();
+    let fetch_producer = producer.clone();
+    let topic_state =
+        tokio::task::spawn_blocking(move || fetch_topic_state(&fetch_producer, &topic))
+            .await
+            .map_err(|err| err.to_string())??;
+
+    match topic_state {
+        TopicState::Writable(partitions) => {
+            log::info!(
+                "Kafka topic '{}' with {partitions} partitions is writable",
+                cli.topic
+            );
+            Ok(())
+        }
+        TopicState::NotWritable(reason) => Err(format!(
+            "Kafka topic '{}' is not writable: {reason}",
+            cli.topic
+        )),
+        TopicState::Missing if cli.create_topic => create_topic(cli).await,
+        TopicState::Missing => Err(format!("Kafka topic '{}' does not exist", cli.topic)),
+    }
+}
+
+/// Checks that Kafka is reachable and describes the configured topic without creating it
+pub async fn describe_topic(cli: &Cli, producer: &KafkaProducer) -> Result<String, String> {
+    let topic = cli.topic.clone();
+    let fetch_producer = producer.clone();
+    let topic_state =
+        tokio::task::spawn_blocking(move || fetch_topic_state(&fetch_producer, &topic))
+            .await
+            .map_err(|err| err.to_string())??;
+
+    match topic_state {
+        TopicState::Writable(partitions) => Ok(format!(
+            "topic '{}' with {partitions} partitions is writable",
+            cli.topic
+        )),
+        TopicState::NotWritable(reason) => {
+            Err(format!("topic '{}' is not writable: {reason}", cli.topic))
+        }
+        TopicState::Missing if cli.create_topic => Ok(format!(
+            "topic '{}' does not exist and will be created on startup",
+            cli.topic
+        )),
+        TopicState::Missing => Err(format!("topic '{}' does not exist", cli.topic)),
+    }
+}
+
+enum TopicState {
+    Writable(usize),
+    NotWritable(String),
+    Missing,
+}
+
+fn fetch_topic_state(producer: &KafkaProducer, topic: &str) -> Result<TopicState, String> {
+    // Auto-creation of the requested topic is disabled by `allow.auto.create.topics`
+    let metadata = producer
+        .client()
+        .fetch_metadata(Some(topic), METADATA_TIMEOUT)
+        .map_err(|err| format!("Cannot fetch Kafka cluster metadata: {err}"))?;
+
+    let Some(topic_metadata) = metadata.topics().iter().find(|t| t.name() == topic) else {
+        return Ok(TopicState::Missing);
+    };
+
+    match topic_metadata.error().map(RDKafkaErrorCode::from) {
+        Some(
+            RDKafkaErrorCode::UnknownTopicOrPartition
+            | RDKafkaErrorCode::UnknownTopic
+            | RDKafkaErrorCode::UnknownPartition,
+        ) => return Ok(TopicState::Missing),
+        Some(err) => return Ok(TopicState::NotWritable(err.to_string())),
+        None => {}
+    }
+
+    if topic_metadata.partitions().is_empty() {
+        return Ok(TopicState::NotWritable("no partitions".to_string()));
+    }
+
+    for partition in topic_metadata.partitions() {
+        if let Some(err) = partition.error() {
+            return Ok(TopicState::NotWritable(format!(
+                "partition {}: {}",
+                partition.id(),
+                RDKafkaErrorCode::from(err)
+            )));
+        }
+        if partition.leader() < 0 {
+            return Ok(TopicState::NotWritable(format!(
+                "partition {} has no leader",
+                partition.id()
+            )));
+        }
+    }
+
+    Ok(TopicState::Writable(topic_metadata.partitions().len()))
+}
+
+fn new_topic(cli: &Cli) -> NewTopic<'_> {
+    let new_topic = NewTopic::new(
+        &cli.topic,
+        cli.topic_partitions,
+        TopicReplication::Fixed(cli.topic_replication_factor),
+    );
+    if cli.topic_compact {
+        return new_topic.set("cleanup.policy", "compact");
+    }
+    new_topic
+}
+
+async fn create_topic(cli: &Cli) -> Result<(), String> {
+    let admin_client = client_config(cli, std::env::vars())?
+        .create_with_context::<_, AdminClient<KafkaContext>>(KafkaContext::without_health(cli)?)
+        .map_err(|err| err.to_string())?;
+
+    let results = admin_client
+        .create_topics(
+            [&new_topic(cli)],
+            &AdminOptions::new().operation_timeout(Some(METADATA_TIMEOUT)),
+        )
+        .await
+        .map_err(|err| format!("Cannot create Kafka topic '{}': {err}", cli.topic))?;
+
+    for result in results {
+        match result {
+            Ok(topic) => log::info!("Created Kafka topic '{topic}'"),
+            Err((topic, RDKafkaErrorCode::TopicAlreadyExists)) => {
+                log::info!("Kafka topic '{topic}' has already been created");
+            }
+            Err((topic, err)) => return Err(format!("Cannot create Kafka topic '{topic}': {err}")),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::Cli;
+    use crate::health::{Health, Status};
+    use crate::kafka::{
+        OAuthTokenProvider, client_config, create_producer, masked_value, new_topic,
+        producer_properties, verify_topic,
+    };
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use clap::Parser;
+    use rdkafka::admin::TopicReplication;
+    use rdkafka::mocking::MockCluster;
+    use rstest::rstest;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[allow(clippy::expect_used)]
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(
+            [
+                "mv64e-rest-to-kafka-gateway",
+                "--token",
+                "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG",
+            ]
+            .iter()
+            .chain(args),
+        )
+        .expect("valid arguments")
+    }
+
+    #[allow(clippy::expect_used)]
+    async fn mock_token_endpoint(router: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let addr = listener.local_addr().expect("local address");
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{addr}/token")
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_plaintext_connection_by_default() {
+        let client_config = client_config(&cli(&[]), std::iter::empty()).expect("client config");
+        assert_eq!(client_config.get("security.protocol"), Some("plaintext"));
+        assert_eq!(client_config.get("sasl.mechanism"), None);
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_disable_topic_auto_creation() {
+        let client_config = client_config(&cli(&[]), std::iter::empty()).expect("client config");
+        assert_eq!(client_config.get("allow.auto.create.topics"), Some("false"));
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_ssl_connection_if_cert_file_given() {
+        let client_config = client_config(
+            &cli(&["--ssl-cert-file", "cert.pem", "--ssl-key-file", "key.pem"]),
+            std::iter::empty(),
+        )
+        .expect("client config");
+        assert_eq!(client_config.get("security.protocol"), Some("ssl"));
+        assert_eq!(
+            client_config.get("ssl.certificate.location"),
+            Some("cert.pem")
+        );
+        assert_eq!(client_config.get("ssl.key.location"), Some("key.pem"));
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_sasl_ssl_with_scram_and_ca_file() {
+        let client_config = client_config(
+            &cli(&[
+                "--security-protocol",
+                "SASL_SSL",
+                "--sasl-mechanism",
+                "SCRAM-SHA-512",
+                "--sasl-username",
+                "gateway",
+                "--sasl-password",
+                "very-secret",
+                "--ssl-ca-file",
+                "ca.pem",
+            ]),
+            std::iter::empty(),
+        )
+        .expect("client config");
+        assert_eq!(client_config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(client_config.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(client_config.get("sasl.username"), Some("gateway"));
+        assert_eq!(client_config.get("sasl.password"), Some("very-secret"));
+        assert_eq!(client_config.get("ssl.ca.location"), Some("ca.pem"));
+    }
+
+    #[test]
+    fn should_reject_sasl_without_password() {
+        assert!(
+            client_config(
+                &cli(&[
+                    "--security-protocol",
+                    "sasl_plaintext",
+                    "--sasl-username",
+                    "gateway",
+                ]),
+                std::iter::empty()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_oauthbearer_without_username_and_password() {
+        let client_config = client_config(
+            &cli(&["--sasl-mechanism", "oauthbearer"]),
+            std::iter::empty(),
+        )
+        .expect("client config");
+        assert_eq!(client_config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(client_config.get("sasl.mechanism"), Some("OAUTHBEARER"));
+        assert_eq!(client_config.get("sasl.username"), None);
+    }
+
+    #[test]
+    fn should_reject_oauthbearer_without_token_endpoint() {
+        assert!(OAuthTokenProvider::new(&cli(&["--sasl-mechanism", "oauthbearer"])).is_err());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_fetch_token_from_token_endpoint() {
+        let token_endpoint = mock_token_endpoint(Router::new().route(
+            "/token",
+            post(|| async { Json(json!({ "access_token": "abc123", "expires_in": 60 })) }),
+        ))
+        .await;
+
+        let token_provider = OAuthTokenProvider::new(&cli(&[
+            "--sasl-mechanism",
+            "oauthbearer",
+            "--oauth-token-endpoint",
+            &token_endpoint,
+            "--oauth-client-id",
+            "gateway",
+            "--oauth-client-secret",
+            "very-secret",
+        ]))
+        .expect("token provider");
+
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("valid time")
+            .as_millis();
+
+        let token = tokio::task::spawn_blocking(move || token_provider.fetch_token())
+            .await
+            .expect("task completed")
+            .expect("token fetched");
+
+        assert_eq!(token.token, "abc123");
+        assert_eq!(token.principal_name, "gateway");
+        assert!(token.lifetime_ms > i64::try_from(now_ms).expect("valid time"));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_return_error_if_token_endpoint_fails() {
+        let token_endpoint = mock_token_endpoint(
+            Router::new().route("/token", post(|| async { StatusCode::UNAUTHORIZED })),
+        )
+        .await;
+
+        let token_provider = OAuthTokenProvider::new(&cli(&[
+            "--sasl-mechanism",
+            "oauthbearer",
+            "--oauth-token-endpoint",
+            &token_endpoint,
+            "--oauth-client-id",
+            "gateway",
+            "--oauth-client-secret",
+            "wrong-secret",
+        ]))
+        .expect("token provider");
+
+        let result = tokio::task::spawn_blocking(move || token_provider.fetch_token())
+            .await
+            .expect("task completed");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_librdkafka_defaults_for_default_delivery_guarantee() {
+        let client_config = client_config(&cli(&[]), std::iter::empty()).expect("client config");
+        assert_eq!(client_config.get("enable.idempotence"), None);
+        assert_eq!(client_config.get("acks"), None);
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_enable_idempotence_for_idempotent_delivery_guarantee() {
+        let client_config = client_config(
+            &cli(&["--delivery-guarantee", "idempotent"]),
+            std::iter::empty(),
+        )
+        .expect("client config");
+        assert_eq!(client_config.get("enable.idempotence"), Some("true"));
+        assert_eq!(client_config.get("acks"), Some("all"));
+        assert_eq!(
+            client_config.get("max.in.flight.requests.per.connection"),
+            Some("5")
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_map_prefixed_env_vars_to_producer_properties() {
+        let properties = producer_properties(
+            &cli(&[]),
+            [
+                ("KAFKA_PRODUCER_LINGER_MS".to_string(), "10".to_string()),
+                (
+                    "KAFKA_PRODUCER_COMPRESSION_TYPE".to_string(),
+                    "lz4".to_string(),
+                ),
+                ("KAFKA_TOPIC".to_string(), "test-topic".to_string()),
+            ]
+            .into_iter(),
+        )
+        .expect("valid properties");
+
+        assert_eq!(
+            properties,
+            vec![
+                ("compression.type".to_string(), "lz4".to_string()),
+                ("linger.ms".to_string(), "10".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_use_producer_properties_from_command_line() {
+        let properties = producer_properties(
+            &cli(&["--producer-property", "acks=all"]),
+            std::iter::empty(),
+        )
+        .expect("valid properties");
+
+        assert_eq!(properties, vec![("acks".to_string(), "all".to_string())]);
+    }
+
+    #[rstest]
+    #[case("KAFKA_PRODUCER_ACKS", "1")]
+    #[case("KAFKA_PRODUCER_ACKS", "0")]
+    #[case("KAFKA_PRODUCER_ENABLE_IDEMPOTENCE", "false")]
+    #[case("KAFKA_PRODUCER_ENABLE_IDEMPOTENCE", "F")]
+    #[case("KAFKA_PRODUCER_ENABLE_IDEMPOTENCE", "0")]
+    #[case("KAFKA_PRODUCER_MAX_IN_FLIGHT_REQUESTS_PER_CONNECTION", "10")]
+    #[case("KAFKA_PRODUCER_RETRIES", "0")]
+    fn should_reject_producer_property_conflicting_with_idempotence(
+        #[case] name: &str,
+        #[case] value: &str,
+    ) {
+        let result = client_config(
+            &cli(&["--delivery-guarantee", "idempotent"]),
+            [(name.to_string(), value.to_string())].into_iter(),
+        );
+
+        assert!(result.is_err_and(|err| err.contains("conflicts with delivery guarantee")));
+    }
+
+    #[rstest]
+    #[case("acks", "all")]
+    #[case("acks", "ALL")]
+    #[case("acks", "-1")]
+    #[case("request.required.acks", "All")]
+    #[case("enable.idempotence", "true")]
+    #[case("enable.idempotence", "True")]
+    #[case("enable.idempotence", "1")]
+    #[case("max.in.flight.requests.per.connection", "1")]
+    #[case("retries", "3")]
+    fn should_accept_producer_property_matching_idempotence(
+        #[case] key: &str,
+        #[case] value: &str,
+    ) {
+        let property = format!("{key}={value}");
+        let client_config = client_config(
+            &cli(&[
+                "--delivery-guarantee",
+                "idempotent",
+                "--producer-property",
+                &property,
+            ]),
+            std::iter::empty(),
+        );
+
+        assert!(client_config.is_ok_and(|client_config| client_config.get(key) == Some(value)));
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_allow_any_acks_for_default_delivery_guarantee() {
+        let client_config = client_config(
+            &cli(&[]),
+            [("KAFKA_PRODUCER_ACKS".to_string(), "1".to_string())].into_iter(),
+        )
+        .expect("client config");
+
+        assert_eq!(client_config.get("acks"), Some("1"));
+    }
+
+    #[test]
+    fn should_reject_unknown_producer_property() {
+        let result = producer_properties(
+            &cli(&[]),
+            [(
+                "KAFKA_PRODUCER_NO_SUCH_PROPERTY".to_string(),
+                "1".to_string(),
+            )]
+            .into_iter(),
+        );
+
+        assert!(result.is_err_and(|err| err.contains("no.such.property")));
+    }
+
+    #[test]
+    fn should_reject_invalid_producer_property_value() {
+        let result = producer_properties(
+            &cli(&["--producer-property", "linger.ms=soon"]),
+            std::iter::empty(),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_reject_producer_property_without_value() {
+        let result = producer_properties(
+            &cli(&["--producer-property", "linger.ms"]),
+            std::iter::empty(),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn should_mask_secret_property_values() {
+        assert_eq!(masked_value("sasl.password", "very-secret"), "********");
+        assert_eq!(masked_value("ssl.key.password", "very-secret"), "********");
+        assert_eq!(masked_value("linger.ms", "10"), "10");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_verify_existing_topic() {
+        let mock_cluster = MockCluster::new(1).expect("mock cluster");
+        mock_cluster
+            .create_topic("test-topic", 2, 1)
+            .expect("topic created");
+
+        let cli = cli(&[
+            "--bootstrap-server",
+            &mock_cluster.bootstrap_servers(),
+            "--topic",
+            "test-topic",
+        ]);
+        let health = Arc::new(Health::default());
+        let producer = create_producer(&cli, Arc::clone(&health)).expect("producer created");
+
+        assert!(verify_topic(&cli, &producer, &health).await.is_ok());
+        assert_eq!(health.readiness().components["topic"].status, Status::Up);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_reject_missing_topic() {
+        let mock_cluster = MockCluster::new(1).expect("mock cluster");
+
+        let cli = cli(&[
+            "--bootstrap-server",
+            &mock_cluster.bootstrap_servers(),
+            "--topic",
+            "missing-topic",
+        ]);
+        let health = Arc::new(Health::default());
+        let producer = create_producer(&cli, Arc::clone(&health)).expect("producer created");
+
+        assert!(verify_topic(&cli, &producer, &health).await.is_err());
+    }
+
+    #[test]
+    fn should_use_configured_topic_settings_for_new_topic() {
+        let cli = cli(&[
+            "--topic",
+            "new-topic",
+            "--topic-partitions",
+            "3",
+            "--topic-replication-factor",
+            "2",
+            "--topic-compact",
+        ]);
+
+        let new_topic = new_topic(&cli);
+
+        assert_eq!(new_topic.name, "new-topic");
+        assert_eq!(new_topic.num_partitions, 3);
+        assert!(matches!(new_topic.replication, TopicReplication::Fixed(2)));
+        assert_eq!(new_topic.config, vec![("cleanup.policy", "compact")]);
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cli::Cli;
+
+/// Upper limit of the exponentially growing lockout duration
+const MAX_LOCKOUT: Duration = Duration::from_hours(1);
+
+/// Maximum number of tracked clients and users
+const MAX_ENTRIES: usize = 100_000;
+
+/// Interval to remove entries of clients and users that are no longer locked
+const PRUNE_INTERVAL: Duration = Duration::from_mins(1);
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_failure) >= MAX_LOCKOUT && self.remaining(now).is_none()
+    }
+}
+
+struct Entries {
+    attempts: HashMap<String, Attempts>,
+    pruned_at: Instant,
+}
+
+/// Client IP and username of an authentication attempt
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LockoutKeys {
+    pub client: Option<String>,
+    pub user: Option<String>,
+}
+
+impl LockoutKeys {
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.client.iter().chain(self.user.iter())
+    }
+}
+
+impl Display for LockoutKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Tracks failed authentication attempts per client IP and per username.
+/// After the configured number of failures, further attempts are rejected for a lockout
+/// duration that doubles with every additional failure.
+///
+/// A locked username only rejects clients that failed themselves, so a locked shared
+/// username cannot be used to lock out other clients.
+pub struct Lockout {
+    max_failures: u32,
+    duration: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl Lockout {
+    pub fn new(max_failures: u32, duration: Duration) -> Self {
+        Self {
+            max_failures,
+            duration,
+            entries: Mutex::new(Entries {
+                attempts: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn from_config(cli: &Cli) -> Self {
+        Self::new(cli.auth_max_failures, Duration::from_secs(cli.auth_lockout))
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_failures > 0 && !self.duration.is_zero()
+    }
+
+    /// Returns the remaining lockout duration if the client or, for clients that failed
+    /// before or are unknown, the username is locked
+    pub fn locked(&self, keys: &LockoutKeys) -> Option<Duration> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let entries = self.entries.lock().ok()?;
+        let now = Instant::now();
+        let client = keys
+            .client
+            .as_ref()
+            .and_then(|client| entries.attempts.get(client))
+            .filter(|attempts| !attempts.is_expired(now));
+        if let Some(remaining) = client.and_then(|attempts| attempts.remaining(now)) {
+            return Some(remaining);
+        }
+        if keys.client.is_some() && client.is_none() {
+            return None;
+        }
+        keys.user
+            .as_ref()
+            .and_then(|user| entries.attempts.get(user)?.remaining(now))
+    }
+
+    /// Records a failed attempt and returns the lockout duration if keys are locked now
+    pub fn failure(&self, keys: &LockoutKeys) -> Option<Duration> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut entries = self.entries.lock().ok()?;
+        let now = Instant::now();
+        if now.duration_since(entries.pruned_at) >= PRUNE_INTERVAL {
+            entries
+                .attempts
+                .retain(|_, attempts| !attempts.is_expired(now));
+            entries.pruned_at = now;
+        }
+
+        let mut lockout = None;
+        for key in keys.iter() {
+            if !entries.attempts.contains_key(key) && entries.attempts.len() >= MAX_ENTRIES {
+                continue;
+            }
+            let attempts = entries.attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if attempts.is_expired(now) {
+                attempts.failures = 0;
+            }
+            attempts.failures += 1;
+            attempts.last_failure = now;
+            if attempts.failures >= self.max_failures {
+                let exponent = (attempts.failures - self.max_failures).min(16);
+                let duration = self.duration.saturating_mul(1 << exponent).min(MAX_LOCKOUT);
+                attempts.locked_until = Some(now + duration);
+                lockout = lockout.max(Some(duration));
+            }
+        }
+        lockout
+    }
+
+    /// Resets failed attempts of the client after successful authentication.
+    /// Failures of the username are kept until they expire, so the real user
+    /// cannot reset the count built up by attackers from other clients.
+    pub fn success(&self, keys: &LockoutKeys) {
+        if let Ok(mut entries) = self.entries.lock()
+            && let Some(client) = &keys.client
+        {
+            entries.attempts.remove(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lockout::{Lockout, LockoutKeys};
+    use std::time::Duration;
+
+    fn keys(client: &str, user: &str) -> LockoutKeys {
+        LockoutKeys {
+            client: Some(format!("client '{client}'")),
+            user: Some(format!("user '{user}'")),
+        }
+    }
+
+    #[test]
+    fn should_lock_after_max_failures() {
+        let lockout = Lockout::new(3, Duration::from_mins(1));
+        let keys = keys("127.0.0.1", "token");
+
+        assert_eq!(lockout.failure(&keys), None);
+        assert_eq!(lockout.failure(&keys), None);
+        assert_eq!(lockout.locked(&keys), None);
+
+        assert_eq!(lockout.failure(&keys), Some(Duration::from_mins(1)));
+        assert!(lockout.locked(&keys).is_some());
+        assert!(lockout.locked(&self::keys("127.0.0.1", "other")).is_some());
+    }
+
+    #[test]
+    fn should_lock_username_only_for_failed_clients() {
+        let lockout = Lockout::new(2, Duration::from_mins(1));
+
+        // Attacker fails for username from different addresses
+        assert_eq!(lockout.failure(&keys("203.0.113.1", "token")), None);
+        assert!(lockout.failure(&keys("203.0.113.2", "token")).is_some());
+
+        assert!(lockout.locked(&keys("203.0.113.1", "token")).is_some());
+        assert!(lockout.locked(&keys("203.0.113.2", "token")).is_some());
+        // Client that has not failed is not affected
+        assert_eq!(lockout.locked(&keys("10.1.2.3", "token")), None);
+        // Unknown client address
+        assert!(
+            lockout
+                .locked(&LockoutKeys {
+                    client: None,
+                    user: Some("user 'token'".to_string()),
+                })
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn should_double_lockout_with_every_further_failure() {
+        let lockout = Lockout::new(1, Duration::from_mins(1));
+        let keys = keys("127.0.0.1", "token");
+
+        assert_eq!(lockout.failure(&keys), Some(Duration::from_mins(1)));
+        assert_eq!(lockout.failure(&keys), Some(Duration::from_mins(2)));
+        assert_eq!(lockout.failure(&keys), Some(Duration::from_mins(4)));
+        for _ in 0..10 {
+            lockout.failure(&keys);
+        }
+        assert_eq!(lockout.failure(&keys), Some(Duration::from_hours(1)));
+    }
+
+    #[test]
+    fn should_reset_client_failures_on_success() {
+        let lockout = Lockout::new(2, Duration::from_mins(1));
+
+        assert_eq!(lockout.failure(&keys("127.0.0.1", "token")), None);
+        lockout.success(&keys("127.0.0.1", "token"));
+        assert_eq!(lockout.failure(&keys("127.0.0.1", "other")), None);
+    }
+
+    #[test]
+    fn should_keep_username_failures_on_success() {
+        let lockout = Lockout::new(2, Duration::from_mins(1));
+
+        assert_eq!(lockout.failure(&keys("203.0.113.1", "token")), None);
+        // Real user authenticates in between
+        lockout.success(&keys("10.1.2.3", "token"));
+        assert!(lockout.failure(&keys("203.0.113.2", "token")).is_some());
+
+        assert!(lockout.locked(&keys("203.0.113.1", "token")).is_some());
+        assert_eq!(lockout.locked(&keys("10.1.2.3", "token")), None);
+    }
+
+    #[test]
+    fn should_not_lock_if_disabled() {
+        let lockout = Lockout::new(0, Duration::from_mins(1));
+        let keys = keys("127.0.0.1", "token");
+
+        for _ in 0..10 {
+            assert_eq!(lockout.failure(&keys), None);
+        }
+        assert_eq!(lockout.locked(&keys), None);
+    }
+}
@@ -1,26 +1,50 @@
 use axum::body::Body;
 use axum::http::StatusCode;
-use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
 use axum::response::{IntoResponse, Response};
-use rdkafka::ClientConfig;
-use rdkafka::producer::FutureProducer;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::sync::{Arc, LazyLock};
-
-#[cfg(not(test))]
-use clap::Parser;
+use std::time::Duration;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 use crate::AppResponse::{
-    Accepted, BadRequest, Unauthorized, UnprocessableContent, UnsupportedContentType,
+    Accepted, BadRequest, Forbidden, Locked, SendFailed, Unauthorized, UnprocessableContent,
+    UnsupportedContentType,
 };
-use crate::auth::is_valid_brypt_hash;
-use crate::cli::Cli;
-use crate::sender::DefaultMtbFileSender;
+use crate::access::AccessControl;
+use crate::auth::{AuthState, Users};
+use crate::cli::{Cli, Command, StartupCheck};
+use crate::config::Reloadable;
+use crate::health::Health;
+use crate::jwt::JwtValidator;
+use crate::lockout::Lockout;
+use crate::sender::{DefaultMtbFileSender, SendError, SendReceipt};
+use crate::signature::RequestSignatures;
+use crate::spool::Spool;
+use crate::tls::{ConnectionInfo, TlsFiles, TlsListener};
 
+mod access;
 mod auth;
 mod cli;
+mod commands;
+mod config;
+mod health;
+mod jwt;
+mod kafka;
+mod lockout;
+mod metrics;
 mod routes;
 mod sender;
+mod signature;
+mod spool;
+#[cfg(test)]
+mod test_util;
+mod tls;
+
+/// Seconds a client should wait before retrying if Kafka is not available
+const RETRY_AFTER_SECONDS: &str = "5";
 
 #[derive(Serialize, Deserialize)]
 struct RecordKey {
@@ -29,12 +53,14 @@ struct RecordKey {
 }
 
 enum AppResponse<'a> {
-    Accepted(&'a str),
+    Accepted(&'a SendReceipt),
     BadRequest,
     Unauthorized,
+    Forbidden,
+    Locked(Duration),
     UnsupportedContentType,
     UnprocessableContent(String),
-    InternalServerError,
+    SendFailed(SendError),
 }
 
 #[allow(clippy::expect_used)]
@@ -53,13 +79,56 @@ impl IntoResponse for AppResponse<'_> {
                 StatusCode::UNPROCESSABLE_ENTITY,
                 format!("This application accepts DNPM data model version 2.1 with content type 'application/json'. {err}")
             ).into_response(),
+            Locked(remaining) => Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(
+                    RETRY_AFTER,
+                    remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0),
+                )
+                .body(Body::empty())
+                .expect("response built"),
+            SendFailed(err) => {
+                let status = match err {
+                    SendError::MessageTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                    _ if err.is_retriable() => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                let problem = json!({
+                    "type": "about:blank",
+                    "title": status.canonical_reason(),
+                    "status": status.as_u16(),
+                    "detail": err.to_string(),
+                });
+                let response = Response::builder()
+                    .status(status)
+                    .header(CONTENT_TYPE, "application/problem+json");
+                if status == StatusCode::SERVICE_UNAVAILABLE {
+                    response.header(RETRY_AFTER, RETRY_AFTER_SECONDS)
+                } else {
+                    response
+                }
+                    .body(Body::from(problem.to_string())).expect("response built")
+            }
             _ => match self {
-                Accepted(request_id) => Response::builder()
-                    .status(StatusCode::ACCEPTED)
-                    .header("X-Request-Id", request_id),
+                Accepted(receipt) => {
+                    let response = Response::builder()
+                        .status(StatusCode::ACCEPTED)
+                        .header("X-Request-Id", &receipt.request_id)
+                        .header(CONTENT_TYPE, "application/json");
+                    return match &receipt.metadata {
+                        Some(metadata) => response
+                            .header("X-Kafka-Topic", &metadata.topic)
+                            .header("X-Kafka-Partition", metadata.partition)
+                            .header("X-Kafka-Offset", metadata.offset),
+                        None => response,
+                    }
+                    .body(Body::from(json!(receipt).to_string()))
+                    .expect("response built");
+                }
                 Unauthorized => Response::builder()
                     .status(StatusCode::UNAUTHORIZED)
                     .header(WWW_AUTHENTICATE, "Basic realm=\"DNPM Kafka Rest Proxy Realm\""),
+                Forbidden => Response::builder().status(StatusCode::FORBIDDEN),
                 _ => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR),
             }
                 .body(Body::empty()).expect("response built"),
@@ -68,86 +137,167 @@ impl IntoResponse for AppResponse<'_> {
 }
 
 #[cfg(not(test))]
-static CONFIG: LazyLock<Cli> = LazyLock::new(Cli::parse);
+static CONFIG: LazyLock<Cli> = LazyLock::new(|| {
+    config::Config::parse_from(std::env::args_os())
+        .unwrap_or_else(|err| err.exit())
+        .cli
+});
 
 #[tokio::main]
 async fn main() -> Result<(), ()> {
-    #[cfg(debug_assertions)]
-    {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::TRACE)
-            .init();
-    }
-
-    #[cfg(not(debug_assertions))]
-    {
-        use std::str::FromStr;
-
-        let trace_level = tracing::Level::from_str(
-            &std::env::var("LOG_LEVEL").unwrap_or_else(|_| "INFO".to_owned()),
-        )
-        .unwrap_or(tracing::Level::INFO);
+    let (log_level, log_level_handle) =
+        tracing_subscriber::reload::Layer::new(config::log_level(&CONFIG));
+    tracing_subscriber::registry()
+        .with(log_level)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    // Filtered by the reloadable log level
+    log::set_max_level(log::LevelFilter::Trace);
 
-        tracing_subscriber::fmt().with_max_level(trace_level).init();
-    }
+    let result = match &CONFIG.command {
+        Some(Command::HashToken {
+            username,
+            cost,
+            password_stdin,
+        }) => commands::hash_token(username, *cost, *password_stdin),
+        Some(Command::VerifyToken {
+            username,
+            password_stdin,
+        }) => commands::verify_token(&CONFIG, username, *password_stdin),
+        Some(Command::CheckConfig { kafka }) => commands::check_config(&CONFIG, *kafka).await,
+        Some(Command::Send { files, request_ids }) => {
+            commands::send(&CONFIG, files, request_ids).await
+        }
+        Some(Command::Delete {
+            patient_ids,
+            file,
+            dry_run,
+            yes,
+        }) => commands::delete(&CONFIG, patient_ids, file.as_deref(), *dry_run, *yes).await,
+        Some(Command::Serve) | None => return serve(log_level_handle).await,
+    };
+    result.map_err(|err| eprintln!("{err}"))
+}
 
-    if !is_valid_brypt_hash(&CONFIG.token) {
-        log::error!("Error starting application: given token is not a valid BCrypt token");
+async fn serve(log_level_handle: config::LogLevelHandle) -> Result<(), ()> {
+    if let Err(err) = auth::validate_config(&CONFIG) {
+        log::error!("Error starting application: {err}");
         return Err(());
     }
 
-    if let Err(err_msg) = start_service().await {
+    if let Err(err_msg) = start_service(log_level_handle).await {
         log::error!("Error starting service: {err_msg}");
+        return Err(());
     }
 
     Ok(())
 }
 
-async fn start_service() -> Result<(), String> {
-    let mut client_config = ClientConfig::new();
-
-    client_config
-        .set("bootstrap.servers", &CONFIG.bootstrap_server)
-        .set("message.timeout.ms", "5000");
-
-    let producer = if CONFIG.ssl_cert_file.is_some() || CONFIG.ssl_key_file.is_some() {
-        // Use SSL
-        client_config
-            .set("security.protocol", "ssl")
-            .set(
-                "ssl.ca.location",
-                CONFIG.ssl_ca_file.clone().unwrap_or_default(),
-            )
-            .set(
-                "ssl.certificate.location",
-                CONFIG.ssl_cert_file.clone().unwrap_or_default(),
-            )
-            .set(
-                "ssl.key.location",
-                CONFIG.ssl_key_file.clone().unwrap_or_default(),
-            );
-        if let Some(ssl_key_password) = &CONFIG.ssl_key_password {
-            client_config.set("ssl.key.password", ssl_key_password);
+/// Creates authentication and access rules of sending systems, reloaded in background
+fn auth_state(log_level: config::LogLevelHandle) -> Result<AuthState, String> {
+    let users = Arc::new(Users::from_config(&CONFIG)?);
+    tokio::spawn(auth::reload_periodically(Arc::downgrade(&users)));
+    let jwt = JwtValidator::new(&CONFIG)?.map(Arc::new);
+    if let Some(jwt) = &jwt {
+        tokio::spawn(jwt::reload_periodically(Arc::downgrade(jwt)));
+    }
+    let access = Arc::new(AccessControl::from_config(&CONFIG));
+    tokio::spawn(config::reload_on_hangup(Reloadable {
+        log_level,
+        users: Arc::clone(&users),
+        access: Arc::clone(&access),
+    }));
+
+    Ok(AuthState {
+        users,
+        jwt,
+        lockout: Arc::new(Lockout::from_config(&CONFIG)),
+        access,
+        signatures: RequestSignatures::from_config(&CONFIG)?.map(Arc::new),
+    })
+}
+
+async fn start_service(log_level: config::LogLevelHandle) -> Result<(), String> {
+    let auth = auth_state(log_level)?;
+    let health = Arc::new(Health::default());
+    let producer = kafka::create_producer(&CONFIG, Arc::clone(&health))?;
+
+    if CONFIG.startup_check != StartupCheck::Disabled
+        && let Err(err) = kafka::verify_topic(&CONFIG, &producer, &health).await
+    {
+        if CONFIG.startup_check == StartupCheck::FailFast {
+            return Err(err);
         }
-        client_config
-            .create::<FutureProducer>()
-            .map_err(|err| err.to_string())?
-    } else {
-        // Plain
-        client_config
-            .create::<FutureProducer>()
-            .map_err(|err| err.to_string())?
-    };
+        log::warn!("Kafka startup check failed, starting in degraded mode: {err}");
+        tokio::spawn(kafka::recheck_topic(
+            &CONFIG,
+            producer.clone(),
+            Arc::clone(&health),
+        ));
+    }
 
-    let sender = Arc::new(DefaultMtbFileSender::new(&CONFIG.topic, producer));
+    let mut sender = DefaultMtbFileSender::new(
+        &CONFIG.topic,
+        producer,
+        Duration::from_millis(CONFIG.queue_timeout),
+    );
+
+    if let Some(spool_dir) = &CONFIG.spool_dir {
+        let spool = Spool::open(
+            spool_dir,
+            CONFIG.spool_max_records,
+            CONFIG.spool_max_size * 1024 * 1024,
+        )
+        .map_err(|err| format!("Cannot open spool '{}': {err}", spool_dir.display()))?;
+        let spool = Arc::new(spool);
+        health.set_spool(Arc::clone(&spool));
+        sender = sender.with_spool(spool);
+        tokio::spawn(sender.clone().replay_spool());
+    }
+
+    let sender = Arc::new(sender);
+
+    let mut app = routes::routes(sender, auth).merge(health::routes(health));
+
+    match &CONFIG.metrics_listen {
+        Some(metrics_listen) => {
+            let listener = tokio::net::TcpListener::bind(metrics_listen)
+                .await
+                .map_err(|err| format!("Cannot listening on '{metrics_listen}': {err}"))?;
+            log::info!("Serving metrics on '{metrics_listen}'");
+            tokio::spawn(async move {
+                if let Err(err) = axum::serve(listener, metrics::routes()).await {
+                    log::error!("Metrics endpoint stopped: {err}");
+                }
+            });
+        }
+        None => app = app.merge(metrics::routes()),
+    }
 
     match tokio::net::TcpListener::bind(&CONFIG.listen).await {
         Ok(listener) => {
-            log::info!("Starting application listening on '{}'", CONFIG.listen);
-            if let Err(err) = axum::serve(listener, routes::routes(sender))
+            let result = if let Some(tls_files) = TlsFiles::new(&CONFIG) {
+                let listener = TlsListener::new(listener, tls_files)?;
+                log::info!(
+                    "Starting application listening on '{}' (HTTPS)",
+                    CONFIG.listen
+                );
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<ConnectionInfo>(),
+                )
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+            } else {
+                log::info!("Starting application listening on '{}'", CONFIG.listen);
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<ConnectionInfo>(),
+                )
                 .with_graceful_shutdown(shutdown_signal())
                 .await
-            {
+            };
+            if let Err(err) = result {
                 return Err(err.to_string());
             }
         }
@@ -176,39 +326,201 @@ async fn shutdown_signal() {
 // Test Configuration
 #[cfg(test)]
 static CONFIG: LazyLock<Cli> = LazyLock::new(|| Cli {
+    command: None,
+    config: None,
+    log_level: None,
     bootstrap_server: "localhost:9094".to_string(),
     topic: "test-topic".to_string(),
+    startup_check: StartupCheck::Disabled,
+    create_topic: false,
+    topic_partitions: 1,
+    topic_replication_factor: 1,
+    topic_compact: false,
     // Basic dG9rZW46dmVyeS1zZWNyZXQ=
-    token: "$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG".to_string(),
+    token: Some("$2y$05$LIIFF4Rbi3iRVA4UIqxzPeTJ0NOn/cV2hDnSKFftAMzbEZRa42xSG".to_string()),
+    users_file: None,
+    auth_cache_ttl: 60,
+    auth_max_failures: 5,
+    auth_lockout: 60,
+    auth_mode: cli::AuthMode::Any,
+    jwt_jwks: None,
+    jwt_issuer: None,
+    jwt_audience: None,
+    jwt_identity_claim: "sub".to_string(),
+    jwt_roles_claim: None,
+    jwt_identity_networks: vec![cli::IdentityNetworks {
+        identity: "mtb-restricted".to_string(),
+        networks: "10.1.2.0/24".parse().into_iter().collect(),
+    }],
+    signature_secrets: None,
+    signature_max_age: 300,
     listen: "0.0.0.0:3000".to_string(),
+    metrics_listen: None,
+    tls_cert: None,
+    tls_key: None,
+    tls_client_ca: None,
+    tls_client_identities: vec![
+        cli::IdentityMapping {
+            identity: "mtb-doc".to_string(),
+            roles: auth::Roles::all(),
+            networks: vec![],
+            name: cli::CertificateName::DnsName("mtb-documentation.example.org".to_string()),
+        },
+        cli::IdentityMapping {
+            identity: "mtb-restricted".to_string(),
+            roles: auth::Roles::all(),
+            networks: "10.1.2.0/24".parse().into_iter().collect(),
+            name: cli::CertificateName::DnsName("mtb-restricted.example.org".to_string()),
+        },
+    ],
+    allowed_networks: vec![],
+    trusted_proxies: vec![],
+    forwarded_header: cli::ForwardedHeader::XForwardedFor,
     ssl_ca_file: None,
     ssl_cert_file: None,
     ssl_key_file: None,
     ssl_key_password: None,
+    security_protocol: None,
+    sasl_mechanism: cli::SaslMechanism::Plain,
+    sasl_username: None,
+    sasl_password: None,
+    oauth_token_endpoint: None,
+    oauth_client_id: None,
+    oauth_client_secret: None,
+    oauth_scope: None,
+    delivery_guarantee: cli::DeliveryGuarantee::Default,
+    producer_properties: vec![],
+    queue_timeout: 1000,
+    spool_dir: None,
+    spool_max_records: 10000,
+    spool_max_size: 1024,
     send_on_invalid: true,
 });
 
 #[cfg(test)]
 mod tests {
-    use axum::http::StatusCode;
-    use axum::http::header::WWW_AUTHENTICATE;
+    use axum::http::header::{CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+    use axum::http::{HeaderValue, StatusCode};
     use axum::response::IntoResponse;
+    use rstest::rstest;
     use uuid::Uuid;
 
-    use crate::AppResponse::{Accepted, InternalServerError, Unauthorized};
+    use crate::AppResponse::{Accepted, Locked, SendFailed, Unauthorized};
+    use crate::sender::{RecordMetadata, SendError, SendReceipt};
+    use axum::body::to_bytes;
+    use serde_json::json;
+    use std::time::Duration;
 
     #[test]
     fn should_return_success_response() {
-        let response = Accepted(&Uuid::new_v4().to_string()).into_response();
+        let receipt = SendReceipt {
+            request_id: Uuid::new_v4().to_string(),
+            metadata: Some(RecordMetadata {
+                topic: "test-topic".to_string(),
+                partition: 1,
+                offset: 42,
+            }),
+        };
+        let response = Accepted(&receipt).into_response();
         assert_eq!(response.status(), StatusCode::ACCEPTED);
         assert!(response.headers().contains_key("x-request-id"));
+        assert_eq!(
+            response
+                .headers()
+                .get("x-kafka-topic")
+                .map(HeaderValue::as_bytes),
+            Some(b"test-topic".as_slice())
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get("x-kafka-partition")
+                .map(HeaderValue::as_bytes),
+            Some(b"1".as_slice())
+        );
+        assert_eq!(
+            response
+                .headers()
+                .get("x-kafka-offset")
+                .map(HeaderValue::as_bytes),
+            Some(b"42".as_slice())
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_return_record_metadata_in_success_response_body() {
+        let receipt = SendReceipt {
+            request_id: "fae56ea7-0000-0000-0000-2b5dde71bb4d".to_string(),
+            metadata: Some(RecordMetadata {
+                topic: "test-topic".to_string(),
+                partition: 1,
+                offset: 42,
+            }),
+        };
+        let body = to_bytes(Accepted(&receipt).into_response().into_body(), 1024)
+            .await
+            .expect("body read");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).expect("valid json"),
+            json!({
+                "requestId": "fae56ea7-0000-0000-0000-2b5dde71bb4d",
+                "topic": "test-topic",
+                "partition": 1,
+                "offset": 42
+            })
+        );
+    }
+
+    #[test]
+    fn should_return_success_response_without_record_metadata_if_spooled() {
+        let receipt = SendReceipt {
+            request_id: Uuid::new_v4().to_string(),
+            metadata: None,
+        };
+        let response = Accepted(&receipt).into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(response.headers().contains_key("x-request-id"));
+        assert!(!response.headers().contains_key("x-kafka-offset"));
     }
 
     #[test]
     fn should_return_error_response() {
-        let response = InternalServerError.into_response();
+        let response = SendFailed(SendError::Serialization).into_response();
         assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
         assert!(!response.headers().contains_key("x-request-id"));
+        assert!(!response.headers().contains_key(RETRY_AFTER));
+        assert_eq!(
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .map(HeaderValue::as_bytes),
+            Some(b"application/problem+json".as_slice())
+        );
+    }
+
+    #[rstest]
+    #[case(SendError::QueueFull)]
+    #[case(SendError::Timeout)]
+    #[case(SendError::Broker("Connection refused".to_string()))]
+    #[case(SendError::SpoolFull)]
+    fn should_return_service_unavailable_response(#[case] err: SendError) {
+        let response = SendFailed(err).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+    }
+
+    #[rstest]
+    #[case(SendError::MessageTooLarge, StatusCode::PAYLOAD_TOO_LARGE)]
+    #[case(SendError::UnknownTopic, StatusCode::INTERNAL_SERVER_ERROR)]
+    #[case(SendError::Spool("No space left on device".to_string()), StatusCode::INTERNAL_SERVER_ERROR)]
+    fn should_return_non_retriable_error_response(
+        #[case] err: SendError,
+        #[case] expected: StatusCode,
+    ) {
+        let response = SendFailed(err).into_response();
+        assert_eq!(response.status(), expected);
+        assert!(!response.headers().contains_key(RETRY_AFTER));
     }
 
     #[test]
@@ -218,4 +530,14 @@ mod tests {
         assert!(response.headers().contains_key(WWW_AUTHENTICATE));
         assert!(!response.headers().contains_key("x-request-id"));
     }
+
+    #[test]
+    fn should_return_too_many_requests_response_with_rounded_up_retry_after() {
+        let response = Locked(Duration::from_millis(59_001)).into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(RETRY_AFTER),
+            Some(&HeaderValue::from_static("60"))
+        );
+    }
 }
@@ -0,0 +1,406 @@
+use axum::Router;
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware::{Next, from_fn};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+/// Prefix of all metric names
+const PREFIX: &str = "mv64e_gateway";
+
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const SIZE_BUCKETS: &[f64] = &[
+    1_024.0,
+    10_240.0,
+    102_400.0,
+    512_000.0,
+    1_048_576.0,
+    5_242_880.0,
+    10_485_760.0,
+];
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+struct Histogram {
+    buckets: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Default)]
+struct HistogramState {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; buckets.len()],
+                ..HistogramState::default()
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        if let Ok(mut state) = self.state.lock() {
+            for (bucket, count) in self.buckets.iter().zip(state.counts.iter_mut()) {
+                if value <= *bucket {
+                    *count += 1;
+                }
+            }
+            state.sum += value;
+            state.count += 1;
+        }
+    }
+
+    fn encode(&self, out: &mut String, name: &str, help: &str) {
+        let Ok(state) = self.state.lock() else {
+            return;
+        };
+        header(out, name, help, "histogram");
+        for (bucket, count) in self.buckets.iter().zip(state.counts.iter()) {
+            let _ = writeln!(out, "{PREFIX}_{name}_bucket{{le=\"{bucket}\"}} {count}");
+        }
+        let _ = writeln!(out, "{PREFIX}_{name}_bucket{{le=\"+Inf\"}} {}", state.count);
+        let _ = writeln!(out, "{PREFIX}_{name}_sum {}", state.sum);
+        let _ = writeln!(out, "{PREFIX}_{name}_count {}", state.count);
+    }
+}
+
+/// Application metrics in Prometheus text format
+pub struct Metrics {
+    http_requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    auth_failures: AtomicU64,
+    auth_lockouts: AtomicU64,
+    auth_locked_requests: AtomicU64,
+    invalid_payloads: Mutex<BTreeMap<u16, u64>>,
+    empty_records: AtomicU64,
+    delivery_duration: Histogram,
+    delivery_failures: AtomicU64,
+    payload_size: Histogram,
+    producer_queue_messages: AtomicU64,
+    producer_queue_bytes: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            http_requests: Mutex::default(),
+            auth_failures: AtomicU64::default(),
+            auth_lockouts: AtomicU64::default(),
+            auth_locked_requests: AtomicU64::default(),
+            invalid_payloads: Mutex::default(),
+            empty_records: AtomicU64::default(),
+            delivery_duration: Histogram::new(LATENCY_BUCKETS),
+            delivery_failures: AtomicU64::default(),
+            payload_size: Histogram::new(SIZE_BUCKETS),
+            producer_queue_messages: AtomicU64::default(),
+            producer_queue_bytes: AtomicU64::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn http_request(&self, route: &str, method: &str, status: u16) {
+        if let Ok(mut http_requests) = self.http_requests.lock() {
+            *http_requests
+                .entry((route.to_string(), method.to_string(), status))
+                .or_default() += 1;
+        }
+    }
+
+    pub fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_lockout(&self) {
+        self.auth_lockouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_locked_request(&self) {
+        self.auth_locked_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn invalid_payload(&self, status: u16) {
+        if let Ok(mut invalid_payloads) = self.invalid_payloads.lock() {
+            *invalid_payloads.entry(status).or_default() += 1;
+        }
+    }
+
+    pub fn empty_record(&self) {
+        self.empty_records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the time between sending a record and its acknowledgement by Kafka
+    pub fn delivery(&self, duration: Duration, success: bool) {
+        if success {
+            self.delivery_duration.observe(duration.as_secs_f64());
+        } else {
+            self.delivery_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn payload_size(&self, bytes: usize) {
+        self.payload_size.observe(bytes as f64);
+    }
+
+    /// Updates producer queue depth as reported by librdkafka statistics
+    pub fn producer_queue(&self, messages: u64, bytes: u64) {
+        self.producer_queue_messages
+            .store(messages, Ordering::Relaxed);
+        self.producer_queue_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "Number of HTTP requests by route, method and status",
+            "counter",
+        );
+        if let Ok(http_requests) = self.http_requests.lock() {
+            for ((route, method, status), value) in http_requests.iter() {
+                let _ = writeln!(
+                    out,
+                    "{PREFIX}_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{status}\"}} {value}",
+                    escape(route),
+                    escape(method)
+                );
+            }
+        }
+
+        counter(
+            &mut out,
+            "auth_failures_total",
+            "Number of requests with invalid authentication",
+            self.auth_failures.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "auth_lockouts_total",
+            "Number of lockouts after repeated invalid authentication",
+            self.auth_lockouts.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "auth_locked_requests_total",
+            "Number of requests rejected during lockout",
+            self.auth_locked_requests.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "invalid_payloads_total",
+            "Number of requests with invalid payload by status",
+            "counter",
+        );
+        if let Ok(invalid_payloads) = self.invalid_payloads.lock() {
+            for (status, value) in invalid_payloads.iter() {
+                let _ = writeln!(
+                    out,
+                    "{PREFIX}_invalid_payloads_total{{status=\"{status}\"}} {value}"
+                );
+            }
+        }
+
+        counter(
+            &mut out,
+            "empty_records_total",
+            "Number of empty records sent for invalid payloads",
+            self.empty_records.load(Ordering::Relaxed),
+        );
+
+        self.delivery_duration.encode(
+            &mut out,
+            "kafka_delivery_duration_seconds",
+            "Time until a record is acknowledged by Kafka",
+        );
+        counter(
+            &mut out,
+            "kafka_delivery_failures_total",
+            "Number of records not acknowledged by Kafka",
+            self.delivery_failures.load(Ordering::Relaxed),
+        );
+
+        self.payload_size
+            .encode(&mut out, "record_payload_bytes", "Size of record payloads");
+
+        gauge(
+            &mut out,
+            "kafka_producer_queue_messages",
+            "Number of messages in the producer queue",
+            self.producer_queue_messages.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "kafka_producer_queue_bytes",
+            "Size of messages in the producer queue",
+            self.producer_queue_bytes.load(Ordering::Relaxed),
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} {metric_type}");
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{PREFIX}_{name} {value}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{PREFIX}_{name} {value}");
+}
+
+fn escape(label_value: &str) -> String {
+    label_value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts requests by matched route, method and response status
+pub async fn track_requests(request: Request<Body>, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+    METRICS.http_request(&route, &method, response.status().as_u16());
+    response
+}
+
+async fn handle_metrics() -> Response {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        METRICS.encode(),
+    )
+        .into_response()
+}
+
+/// Metrics route does not require authentication
+pub fn routes() -> Router {
+    Router::new()
+        .route("/metrics", get(handle_metrics))
+        .layer(from_fn(track_requests))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{Metrics, routes};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[test]
+    fn should_encode_http_request_counters() {
+        let metrics = Metrics::default();
+        metrics.http_request("/mtb/etl/patient-record", "POST", 202);
+        metrics.http_request("/mtb/etl/patient-record", "POST", 202);
+        metrics.http_request("/mtb/etl/patient/{patient_id}", "DELETE", 401);
+
+        let encoded = metrics.encode();
+
+        assert!(encoded.contains("# TYPE mv64e_gateway_http_requests_total counter"));
+        assert!(encoded.contains(
+            "mv64e_gateway_http_requests_total{route=\"/mtb/etl/patient-record\",method=\"POST\",status=\"202\"} 2"
+        ));
+        assert!(encoded.contains(
+            "mv64e_gateway_http_requests_total{route=\"/mtb/etl/patient/{patient_id}\",method=\"DELETE\",status=\"401\"} 1"
+        ));
+    }
+
+    #[test]
+    fn should_encode_counters_and_gauges() {
+        let metrics = Metrics::default();
+        metrics.auth_failure();
+        metrics.invalid_payload(422);
+        metrics.empty_record();
+        metrics.producer_queue(3, 1024);
+
+        let encoded = metrics.encode();
+
+        assert!(encoded.contains("mv64e_gateway_auth_failures_total 1"));
+        assert!(encoded.contains("mv64e_gateway_invalid_payloads_total{status=\"422\"} 1"));
+        assert!(encoded.contains("mv64e_gateway_empty_records_total 1"));
+        assert!(encoded.contains("mv64e_gateway_kafka_producer_queue_messages 3"));
+        assert!(encoded.contains("mv64e_gateway_kafka_producer_queue_bytes 1024"));
+    }
+
+    #[test]
+    fn should_encode_histograms() {
+        let metrics = Metrics::default();
+        metrics.delivery(Duration::from_millis(20), true);
+        metrics.delivery(Duration::from_millis(300), true);
+        metrics.delivery(Duration::from_secs(5), false);
+        metrics.payload_size(2_000);
+
+        let encoded = metrics.encode();
+
+        assert!(
+            encoded.contains("mv64e_gateway_kafka_delivery_duration_seconds_bucket{le=\"0.01\"} 0")
+        );
+        assert!(
+            encoded
+                .contains("mv64e_gateway_kafka_delivery_duration_seconds_bucket{le=\"0.025\"} 1")
+        );
+        assert!(
+            encoded.contains("mv64e_gateway_kafka_delivery_duration_seconds_bucket{le=\"0.5\"} 2")
+        );
+        assert!(
+            encoded.contains("mv64e_gateway_kafka_delivery_duration_seconds_bucket{le=\"+Inf\"} 2")
+        );
+        assert!(encoded.contains("mv64e_gateway_kafka_delivery_duration_seconds_count 2"));
+        assert!(encoded.contains("mv64e_gateway_kafka_delivery_failures_total 1"));
+        assert!(encoded.contains("mv64e_gateway_record_payload_bytes_bucket{le=\"1024\"} 0"));
+        assert!(encoded.contains("mv64e_gateway_record_payload_bytes_bucket{le=\"10240\"} 1"));
+        assert!(encoded.contains("mv64e_gateway_record_payload_bytes_sum 2000"));
+    }
+
+    #[tokio::test]
+    #[allow(clippy::expect_used)]
+    async fn should_serve_metrics_without_authentication() {
+        let response = routes()
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .expect("request built"),
+            )
+            .await
+            .expect("response");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("body read");
+        assert!(
+            String::from_utf8_lossy(&body)
+                .contains("# TYPE mv64e_gateway_http_requests_total counter")
+        );
+    }
+}
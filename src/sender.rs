@@ -1,20 +1,28 @@
 use async_trait::async_trait;
 use mv64e_mtb_dto::Mtb;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
 use rdkafka::message::{Header, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::FutureRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedMutexGuard;
 use uuid::Uuid;
 
 #[cfg(test)]
 use mockall::automock;
 
 use crate::RecordKey;
+use crate::kafka::KafkaProducer;
+use crate::metrics::METRICS;
+use crate::spool::{Spool, SpoolError, SpooledRecord};
 
 pub type DynMtbFileSender = Arc<dyn MtbFileSender + Send + Sync>;
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
 pub enum RequestMethod {
     Post,
     Delete,
@@ -29,6 +37,104 @@ impl Display for RequestMethod {
     }
 }
 
+/// Position of a record written to Kafka
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RecordMetadata {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// Result of an accepted record.
+/// A record without metadata has been spooled and will be sent later.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendReceipt {
+    pub request_id: String,
+    #[serde(flatten)]
+    pub metadata: Option<RecordMetadata>,
+}
+
+impl Display for SendReceipt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.metadata {
+            Some(metadata) => write!(
+                f,
+                "Record '{}' written to topic '{}' partition {} offset {}",
+                self.request_id, metadata.topic, metadata.partition, metadata.offset
+            ),
+            None => write!(f, "Record '{}' spooled", self.request_id),
+        }
+    }
+}
+
+/// Reason why a record could not be sent
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    Serialization,
+    QueueFull,
+    MessageTooLarge,
+    Timeout,
+    Broker(String),
+    UnknownTopic,
+    SpoolFull,
+    Spool(String),
+}
+
+impl SendError {
+    /// Sending the record again later may succeed
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            SendError::QueueFull | SendError::Timeout | SendError::Broker(_) | SendError::SpoolFull
+        )
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Serialization => write!(f, "Record could not be serialized"),
+            SendError::QueueFull => write!(f, "Kafka producer queue is full"),
+            SendError::MessageTooLarge => write!(f, "Record exceeds maximum message size"),
+            SendError::Timeout => write!(f, "Record was not acknowledged by Kafka in time"),
+            SendError::Broker(err) => write!(f, "Kafka broker not available: {err}"),
+            SendError::UnknownTopic => write!(f, "Kafka topic does not exist"),
+            SendError::SpoolFull => write!(f, "Kafka not available and spool is full"),
+            SendError::Spool(err) => write!(f, "Kafka not available and record not spooled: {err}"),
+        }
+    }
+}
+
+impl From<KafkaError> for SendError {
+    fn from(err: KafkaError) -> Self {
+        match err.rdkafka_error_code() {
+            Some(RDKafkaErrorCode::QueueFull) => SendError::QueueFull,
+            Some(RDKafkaErrorCode::MessageSizeTooLarge | RDKafkaErrorCode::InvalidMessageSize) => {
+                SendError::MessageTooLarge
+            }
+            Some(RDKafkaErrorCode::MessageTimedOut | RDKafkaErrorCode::RequestTimedOut) => {
+                SendError::Timeout
+            }
+            Some(
+                RDKafkaErrorCode::UnknownTopic
+                | RDKafkaErrorCode::UnknownTopicOrPartition
+                | RDKafkaErrorCode::UnknownPartition,
+            ) => SendError::UnknownTopic,
+            _ => SendError::Broker(err.to_string()),
+        }
+    }
+}
+
+impl From<SpoolError> for SendError {
+    fn from(err: SpoolError) -> Self {
+        match err {
+            SpoolError::Full => SendError::SpoolFull,
+            SpoolError::Io(err) => SendError::Spool(err),
+        }
+    }
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait MtbFileSender {
@@ -37,29 +143,81 @@ pub trait MtbFileSender {
         mtb: Mtb,
         method: RequestMethod,
         request_id: Option<String>,
-    ) -> Result<String, ()>;
+        client_identity: Option<String>,
+    ) -> Result<SendReceipt, SendError>;
 
     async fn send_empty(
         &self,
         method: RequestMethod,
         request_id: Option<String>,
-    ) -> Result<String, ()>;
+        client_identity: Option<String>,
+    ) -> Result<SendReceipt, SendError>;
+}
+
+/// Locks held while a record of a patient is sent or spooled.
+/// Entries are removed as soon as no request of the patient is in progress.
+#[derive(Default)]
+struct PatientLocks(Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+struct PatientGuard<'a> {
+    locks: &'a PatientLocks,
+    patient_id: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl PatientLocks {
+    async fn lock(&self, patient_id: &str) -> PatientGuard<'_> {
+        let lock = self.0.lock().map_or_else(
+            |_| Arc::default(),
+            |mut locks| Arc::clone(locks.entry(patient_id.to_string()).or_default()),
+        );
+        PatientGuard {
+            locks: self,
+            patient_id: patient_id.to_string(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+impl Drop for PatientGuard<'_> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        if let Ok(mut locks) = self.locks.0.lock()
+            && locks
+                .get(&self.patient_id)
+                .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.patient_id);
+        }
+    }
 }
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone)]
 pub struct DefaultMtbFileSender {
     topic: String,
-    producer: FutureProducer,
+    producer: KafkaProducer,
+    queue_timeout: Duration,
+    spool: Option<Arc<Spool>>,
+    patient_locks: Arc<PatientLocks>,
 }
 
 impl DefaultMtbFileSender {
-    pub fn new(topic: &str, producer: FutureProducer) -> Self {
+    pub fn new(topic: &str, producer: KafkaProducer, queue_timeout: Duration) -> Self {
         Self {
             topic: topic.to_string(),
             producer,
+            queue_timeout,
+            spool: None,
+            patient_locks: Arc::default(),
         }
     }
+
+    /// Records that cannot be sent are written to the spool and sent later
+    pub fn with_spool(mut self, spool: Arc<Spool>) -> Self {
+        self.spool = Some(spool);
+        self
+    }
 }
 
 #[async_trait]
@@ -69,13 +227,14 @@ impl MtbFileSender for DefaultMtbFileSender {
         mtb: Mtb,
         method: RequestMethod,
         request_id: Option<String>,
-    ) -> Result<String, ()> {
+        client_identity: Option<String>,
+    ) -> Result<SendReceipt, SendError> {
         match serde_json::to_string(&mtb) {
             Ok(json) => {
-                self.send_message(&json, &mtb.patient.id, method, request_id)
+                self.send_message(&json, &mtb.patient.id, method, request_id, client_identity)
                     .await
             }
-            Err(_) => Err(()),
+            Err(_) => Err(SendError::Serialization),
         }
     }
 
@@ -83,8 +242,10 @@ impl MtbFileSender for DefaultMtbFileSender {
         &self,
         method: RequestMethod,
         request_id: Option<String>,
-    ) -> Result<String, ()> {
-        self.send_message("{}", "", method, request_id).await
+        client_identity: Option<String>,
+    ) -> Result<SendReceipt, SendError> {
+        self.send_message("{}", "", method, request_id, client_identity)
+            .await
     }
 }
 
@@ -95,40 +256,236 @@ impl DefaultMtbFileSender {
         patient_id: &str,
         method: RequestMethod,
         request_id: Option<String>,
-    ) -> Result<String, ()> {
+        client_identity: Option<String>,
+    ) -> Result<SendReceipt, SendError> {
         let request_id = request_id.unwrap_or_else(|| Uuid::new_v4().to_string());
 
         let record_key = RecordKey {
             patient_id: patient_id.to_string(),
         };
 
-        let record_headers = OwnedHeaders::default()
+        let record = SpooledRecord {
+            request_id: request_id.clone(),
+            request_method: method,
+            key: serde_json::to_string(&record_key).map_err(|_| SendError::Serialization)?,
+            payload: payload.to_string(),
+            client_identity,
+        };
+        METRICS.payload_size(record.payload.len());
+
+        let Some(spool) = &self.spool else {
+            return self.send_record(&record).await.map(|metadata| SendReceipt {
+                request_id,
+                metadata: Some(metadata),
+            });
+        };
+
+        // Records must not overtake spooled or still pending records of the same patient:
+        // a pending record may fail and be spooled after a newer record has been sent.
+        let _patient_guard = self.patient_locks.lock(patient_id).await;
+
+        if spool.is_empty() {
+            match self.send_record(&record).await {
+                Ok(metadata) => {
+                    return Ok(SendReceipt {
+                        request_id,
+                        metadata: Some(metadata),
+                    });
+                }
+                Err(err) if !err.is_retriable() => return Err(err),
+                Err(err) => log::warn!("Record '{request_id}' could not be sent to Kafka: {err}"),
+            }
+        }
+
+        let spool = Arc::clone(spool);
+        match tokio::task::spawn_blocking(move || spool.push(&record)).await {
+            Ok(Ok(())) => {
+                log::warn!("Record '{request_id}' was spooled");
+                Ok(SendReceipt {
+                    request_id,
+                    metadata: None,
+                })
+            }
+            Ok(Err(err)) => {
+                log::error!("Record '{request_id}' could not be spooled: {err}");
+                Err(err.into())
+            }
+            Err(err) => {
+                log::error!("Record '{request_id}' could not be spooled: {err}");
+                Err(SendError::Spool(err.to_string()))
+            }
+        }
+    }
+
+    async fn send_record(&self, record: &SpooledRecord) -> Result<RecordMetadata, SendError> {
+        let mut record_headers = OwnedHeaders::default()
             .insert(Header {
                 key: "requestId",
-                value: Some(&request_id),
+                value: Some(&record.request_id),
             })
             .insert(Header {
                 key: "requestMethod",
-                value: Some(&method.to_string()),
+                value: Some(&record.request_method.to_string()),
             })
             .insert(Header {
                 key: "contentType",
                 value: Some("application/vnd.dnpm.v2.mtb+json"),
             });
+        if let Some(client_identity) = &record.client_identity {
+            record_headers = record_headers.insert(Header {
+                key: "clientIdentity",
+                value: Some(client_identity),
+            });
+        }
 
-        let record_key = serde_json::to_string(&record_key).map_err(|_| ())?;
-
-        self.producer
+        let start = Instant::now();
+        let delivery = self
+            .producer
             .send(
                 FutureRecord::to(&self.topic)
-                    .key(&record_key)
+                    .key(&record.key)
                     .headers(record_headers)
-                    .payload(payload),
-                Duration::from_secs(1),
+                    .payload(&record.payload),
+                self.queue_timeout,
             )
-            .await
-            .map_err(|_| ())
-            .map(|_| ())?;
-        Ok(request_id)
+            .await;
+        METRICS.delivery(start.elapsed(), delivery.is_ok());
+
+        delivery
+            .map_err(|(err, _)| SendError::from(err))
+            .map(|delivery| RecordMetadata {
+                topic: self.topic.clone(),
+                partition: delivery.partition,
+                offset: delivery.offset,
+            })
+    }
+
+    /// Sends spooled records in order as soon as Kafka is reachable again
+    pub async fn replay_spool(self) {
+        let Some(spool) = self.spool.clone() else {
+            return;
+        };
+
+        loop {
+            let next = {
+                let spool = Arc::clone(&spool);
+                tokio::task::spawn_blocking(move || spool.peek()).await
+            };
+
+            let (sequence, record) = match next {
+                Ok(Ok(Some(next))) => next,
+                Ok(Ok(None)) => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Ok(Err(err)) => {
+                    log::error!("Cannot read spool: {err}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+                Err(err) => {
+                    log::error!("Cannot read spool: {err}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            };
+
+            let metadata = match self.send_record(&record).await {
+                Ok(metadata) => metadata,
+                Err(err) if err.is_retriable() => {
+                    log::warn!(
+                        "Spooled record '{}' could not be sent to Kafka: {err}",
+                        record.request_id
+                    );
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+                Err(err) => {
+                    // Sending again will never succeed and would block all following records
+                    let failed = {
+                        let spool = Arc::clone(&spool);
+                        tokio::task::spawn_blocking(move || spool.fail(sequence)).await
+                    };
+                    match failed {
+                        Ok(Ok(failed_path)) => log::error!(
+                            "Spooled record '{}' cannot be sent to Kafka, moved to '{}': {err}",
+                            record.request_id,
+                            failed_path.display()
+                        ),
+                        Ok(Err(err)) => log::error!(
+                            "Spooled record '{}' cannot be sent to Kafka and not moved aside: {err}",
+                            record.request_id
+                        ),
+                        Err(err) => log::error!(
+                            "Spooled record '{}' cannot be sent to Kafka and not moved aside: {err}",
+                            record.request_id
+                        ),
+                    }
+                    continue;
+                }
+            };
+
+            let removed = {
+                let spool = Arc::clone(&spool);
+                tokio::task::spawn_blocking(move || spool.remove(sequence)).await
+            };
+            match removed {
+                Ok(Ok(())) => log::info!(
+                    "Spooled record '{}' written to topic '{}' partition {} offset {}, {} records left",
+                    record.request_id,
+                    metadata.topic,
+                    metadata.partition,
+                    metadata.offset,
+                    spool.len()
+                ),
+                Ok(Err(err)) => log::error!(
+                    "Spooled record '{}' sent but not removed from spool: {err}",
+                    record.request_id
+                ),
+                Err(err) => log::error!(
+                    "Spooled record '{}' sent but not removed from spool: {err}",
+                    record.request_id
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sender::PatientLocks;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn should_serialize_requests_of_same_patient() {
+        let locks = PatientLocks::default();
+
+        let guard = locks.lock("P1").await;
+        let pending = tokio::time::timeout(Duration::from_millis(50), locks.lock("P1")).await;
+        assert!(pending.is_err());
+
+        drop(guard);
+        let pending = tokio::time::timeout(Duration::from_millis(50), locks.lock("P1")).await;
+        assert!(pending.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_not_block_requests_of_other_patients() {
+        let locks = PatientLocks::default();
+
+        let _guard = locks.lock("P1").await;
+        let other = tokio::time::timeout(Duration::from_millis(50), locks.lock("P2")).await;
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn should_remove_released_locks() {
+        let locks = PatientLocks::default();
+
+        let guard = locks.lock("P1").await;
+        assert!(locks.0.lock().is_ok_and(|locks| locks.contains_key("P1")));
+
+        drop(guard);
+        assert!(locks.0.lock().is_ok_and(|locks| locks.is_empty()));
     }
 }
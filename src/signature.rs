@@ -0,0 +1,402 @@
+use axum::body::{Body, to_bytes};
+use axum::extract::State;
+use axum::http::uri::PathAndQuery;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use ring::{digest, hmac};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::AppResponse::Unauthorized;
+use crate::auth::ClientIdentity;
+use crate::cli::Cli;
+use crate::routes::MAX_BODY_SIZE;
+
+/// Header with the Unix timestamp in seconds the request was signed at
+pub const TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+
+/// Header with the Base64 encoded HMAC-SHA256 signature of the request
+pub const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Minimum length of a secret to resist brute forcing
+const MIN_SECRET_LEN: usize = 16;
+
+/// Maximum number of used signatures kept to detect replays
+const MAX_SEEN_SIGNATURES: usize = 100_000;
+
+/// Interval to remove used signatures whose timestamp is no longer valid
+const PRUNE_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Returns the string to be signed: method, path with query, timestamp and the
+/// hex encoded SHA-256 hash of the body, separated by newlines
+fn signing_string(method: &str, path: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut signing_string = format!("{method}\n{path}\n{timestamp}\n");
+    for byte in digest::digest(&digest::SHA256, body).as_ref() {
+        let _ = write!(signing_string, "{byte:02x}");
+    }
+    signing_string
+}
+
+/// Returns the Base64 encoded signature of a request
+#[cfg(test)]
+pub fn sign(secret: &str, method: &str, path: &str, timestamp: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    BASE64_STANDARD.encode(hmac::sign(
+        &key,
+        signing_string(method, path, timestamp, body).as_bytes(),
+    ))
+}
+
+/// Parses lines `identity:secret`. Empty lines and lines starting with `#` are skipped.
+pub fn parse_secrets(content: &str) -> (HashMap<String, hmac::Key>, Vec<String>) {
+    let mut secrets = HashMap::new();
+    let mut errors = Vec::new();
+    for (line_number, line) in content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+    {
+        match line.split_once(':') {
+            Some((identity, _)) if secrets.contains_key(identity.trim()) => {
+                errors.push(format!(
+                    "line {line_number}: duplicate identity '{}'",
+                    identity.trim()
+                ));
+            }
+            Some((identity, secret))
+                if !identity.trim().is_empty() && secret.len() >= MIN_SECRET_LEN =>
+            {
+                secrets.insert(
+                    identity.trim().to_string(),
+                    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+                );
+            }
+            Some((identity, _)) if !identity.trim().is_empty() => errors.push(format!(
+                "line {line_number}: secret of '{}' is shorter than {MIN_SECRET_LEN} characters",
+                identity.trim()
+            )),
+            _ => errors.push(format!("line {line_number}: expected 'identity:secret'")),
+        }
+    }
+    (secrets, errors)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default()
+}
+
+/// Signatures already used with the Unix time they expire at
+struct SeenSignatures {
+    expires_at: HashMap<Vec<u8>, u64>,
+    pruned_at: Instant,
+}
+
+/// Verifies HMAC request signatures of sending systems and rejects replayed requests
+pub struct RequestSignatures {
+    secrets: HashMap<String, hmac::Key>,
+    max_age: Duration,
+    seen: Mutex<SeenSignatures>,
+}
+
+impl RequestSignatures {
+    pub fn new(secrets: HashMap<String, hmac::Key>, max_age: Duration) -> Self {
+        Self {
+            secrets,
+            max_age,
+            seen: Mutex::new(SeenSignatures {
+                expires_at: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn from_config(cli: &Cli) -> Result<Option<Self>, String> {
+        let Some(path) = &cli.signature_secrets else {
+            return Ok(None);
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Cannot read signature secrets '{}': {err}", path.display()))?;
+        let (secrets, errors) = parse_secrets(&content);
+        if !errors.is_empty() {
+            return Err(format!(
+                "Invalid entry in '{}', {}",
+                path.display(),
+                errors.join(", ")
+            ));
+        }
+        Ok(Some(Self::new(
+            secrets,
+            Duration::from_secs(cli.signature_max_age),
+        )))
+    }
+
+    /// Verifies timestamp and signature of a request and records the signature
+    /// to reject replays while the timestamp is valid
+    pub fn verify(
+        &self,
+        identity: &ClientIdentity,
+        method: &str,
+        path: &str,
+        timestamp: &str,
+        signature: &str,
+        body: &[u8],
+    ) -> Result<(), String> {
+        let key = self
+            .secrets
+            .get(&identity.0)
+            .ok_or("no secret for client")?;
+
+        let signed_at = timestamp
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| "invalid timestamp")?;
+        let now = unix_time();
+        if now.abs_diff(signed_at) > self.max_age.as_secs() {
+            return Err(format!(
+                "timestamp {signed_at} is not within {}s of the current time",
+                self.max_age.as_secs()
+            ));
+        }
+
+        let signature = BASE64_STANDARD
+            .decode(signature.trim())
+            .map_err(|_| "invalid signature encoding")?;
+        hmac::verify(
+            key,
+            signing_string(method, path, timestamp, body).as_bytes(),
+            &signature,
+        )
+        .map_err(|_| "signature does not match")?;
+
+        let mut seen = self.seen.lock().map_err(|_| "replay cache unavailable")?;
+        if seen.pruned_at.elapsed() >= PRUNE_INTERVAL {
+            seen.expires_at.retain(|_, expires_at| *expires_at >= now);
+            seen.pruned_at = Instant::now();
+        }
+        if seen
+            .expires_at
+            .get(&signature)
+            .is_some_and(|expires_at| *expires_at >= now)
+        {
+            return Err("signature already used".into());
+        }
+        // Forgetting signatures would allow replays, so reject until expired ones are pruned
+        if seen.expires_at.len() >= MAX_SEEN_SIGNATURES {
+            return Err("too many signed requests, replay cache is full".into());
+        }
+        seen.expires_at
+            .insert(signature, signed_at + self.max_age.as_secs());
+        Ok(())
+    }
+}
+
+/// Verifies the signature of authenticated requests if request signing is configured
+pub async fn check_signature(
+    State(signatures): State<Option<Arc<RequestSignatures>>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(signatures) = signatures else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY_SIZE).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let Some(identity) = parts.extensions.get::<ClientIdentity>() else {
+        return Unauthorized.into_response();
+    };
+    let header = |name| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(PathAndQuery::as_str)
+        .unwrap_or_default();
+
+    let result = match (header(TIMESTAMP_HEADER), header(SIGNATURE_HEADER)) {
+        (Some(timestamp), Some(signature)) => signatures.verify(
+            identity,
+            parts.method.as_str(),
+            path,
+            timestamp,
+            signature,
+            &body,
+        ),
+        _ => Err("missing signature".to_string()),
+    };
+    if let Err(err) = result {
+        log::warn!("Invalid request signature of client '{identity}': {err}");
+        return Unauthorized.into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::ClientIdentity;
+    use crate::signature::{
+        MAX_SEEN_SIGNATURES, RequestSignatures, parse_secrets, sign, unix_time,
+    };
+    use rstest::rstest;
+    use std::time::Duration;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn signatures() -> RequestSignatures {
+        let (secrets, _) = parse_secrets(&format!("mtb-doc:{SECRET}"));
+        RequestSignatures::new(secrets, Duration::from_mins(5))
+    }
+
+    fn identity(name: &str) -> ClientIdentity {
+        ClientIdentity(name.to_string())
+    }
+
+    #[test]
+    fn should_verify_signed_request() {
+        let timestamp = unix_time().to_string();
+        let signature = sign(SECRET, "POST", "/mtb/etl/patient-record", &timestamp, b"{}");
+
+        assert_eq!(
+            signatures().verify(
+                &identity("mtb-doc"),
+                "POST",
+                "/mtb/etl/patient-record",
+                &timestamp,
+                &signature,
+                b"{}"
+            ),
+            Ok(())
+        );
+    }
+
+    #[rstest]
+    #[case::other_client("pathology", "POST", "/mtb/etl/patient-record", b"{}")]
+    #[case::other_method("mtb-doc", "DELETE", "/mtb/etl/patient-record", b"{}")]
+    #[case::other_path("mtb-doc", "POST", "/mtb/etl/patient-record/validate", b"{}")]
+    #[case::other_body("mtb-doc", "POST", "/mtb/etl/patient-record", b"{ }")]
+    fn should_reject_tampered_request(
+        #[case] client: &str,
+        #[case] method: &str,
+        #[case] path: &str,
+        #[case] body: &[u8],
+    ) {
+        let timestamp = unix_time().to_string();
+        let signature = sign(SECRET, "POST", "/mtb/etl/patient-record", &timestamp, b"{}");
+
+        assert!(
+            signatures()
+                .verify(
+                    &identity(client),
+                    method,
+                    path,
+                    &timestamp,
+                    &signature,
+                    body
+                )
+                .is_err()
+        );
+    }
+
+    #[rstest]
+    #[case(unix_time() - 301)]
+    #[case(unix_time() + 301)]
+    fn should_reject_stale_timestamp(#[case] timestamp: u64) {
+        let timestamp = timestamp.to_string();
+        let signature = sign(SECRET, "DELETE", "/mtb/etl/patient/P1", &timestamp, b"");
+
+        assert!(
+            signatures()
+                .verify(
+                    &identity("mtb-doc"),
+                    "DELETE",
+                    "/mtb/etl/patient/P1",
+                    &timestamp,
+                    &signature,
+                    b""
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn should_reject_replayed_request() {
+        let signatures = signatures();
+        let timestamp = unix_time().to_string();
+        let signature = sign(SECRET, "DELETE", "/mtb/etl/patient/P1", &timestamp, b"");
+        let verify = || {
+            signatures.verify(
+                &identity("mtb-doc"),
+                "DELETE",
+                "/mtb/etl/patient/P1",
+                &timestamp,
+                &signature,
+                b"",
+            )
+        };
+
+        assert_eq!(verify(), Ok(()));
+        assert_eq!(verify(), Err("signature already used".to_string()));
+    }
+
+    #[test]
+    fn should_reject_request_if_replay_cache_is_full() {
+        let signatures = signatures();
+        if let Ok(mut seen) = signatures.seen.lock() {
+            let expires_at = unix_time() + 300;
+            for index in 0..MAX_SEEN_SIGNATURES {
+                seen.expires_at
+                    .insert(index.to_be_bytes().to_vec(), expires_at);
+            }
+        }
+        let timestamp = unix_time().to_string();
+        let signature = sign(SECRET, "DELETE", "/mtb/etl/patient/P1", &timestamp, b"");
+
+        assert!(
+            signatures
+                .verify(
+                    &identity("mtb-doc"),
+                    "DELETE",
+                    "/mtb/etl/patient/P1",
+                    &timestamp,
+                    &signature,
+                    b"",
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn should_report_invalid_secrets() {
+        let (secrets, errors) = parse_secrets(&format!(
+            "# comment\n\nmtb-doc:{SECRET}\nmtb-doc:{SECRET}\npathology:short\n:{SECRET}\ndpo\n"
+        ));
+
+        assert_eq!(secrets.len(), 1);
+        assert_eq!(
+            errors,
+            vec![
+                "line 4: duplicate identity 'mtb-doc'",
+                "line 5: secret of 'pathology' is shorter than 16 characters",
+                "line 6: expected 'identity:secret'",
+                "line 7: expected 'identity:secret'",
+            ]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::sender::RequestMethod;
+
+/// Record as sent to Kafka, kept in the spool until Kafka is reachable again
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpooledRecord {
+    pub request_id: String,
+    pub request_method: RequestMethod,
+    pub key: String,
+    pub payload: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_identity: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SpoolError {
+    Full,
+    Io(String),
+}
+
+impl Display for SpoolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpoolError::Full => write!(f, "Spool limit reached"),
+            SpoolError::Io(err) => write!(f, "Spool IO error: {err}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for SpoolError {
+    fn from(err: std::io::Error) -> Self {
+        SpoolError::Io(err.to_string())
+    }
+}
+
+struct SpoolEntry {
+    sequence: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct SpoolState {
+    entries: VecDeque<SpoolEntry>,
+    next_sequence: u64,
+    size: u64,
+}
+
+/// Persistent FIFO queue of records in a local directory.
+///
+/// Each record is written to a temporary file, synced to disk and renamed afterwards,
+/// so a crash never leaves a partially written record behind.
+///
+/// Writers are serialized by a separate lock, so the state lock is never held while
+/// waiting for the disk and can be used from async code.
+pub struct Spool {
+    dir: PathBuf,
+    max_records: usize,
+    max_size: u64,
+    write: Mutex<()>,
+    state: Mutex<SpoolState>,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, max_records: usize, max_size: u64) -> Result<Self, SpoolError> {
+        fs::create_dir_all(dir)?;
+
+        let mut state = SpoolState::default();
+        let mut entries = vec![];
+
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                // Incomplete write before crash
+                Some("tmp") => fs::remove_file(&path)?,
+                Some("json") => {
+                    if let Some(sequence) = path
+                        .file_stem()
+                        .and_then(|file_stem| file_stem.to_str())
+                        .and_then(|file_stem| file_stem.parse::<u64>().ok())
+                    {
+                        entries.push(SpoolEntry {
+                            sequence,
+                            size: fs::metadata(&path)?.len(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        entries.sort_by_key(|entry| entry.sequence);
+        state.next_sequence = entries.last().map_or(0, |entry| entry.sequence + 1);
+        state.size = entries.iter().map(|entry| entry.size).sum();
+        state.entries = entries.into();
+
+        if !state.entries.is_empty() {
+            log::info!(
+                "Found {} spooled records in '{}'",
+                state.entries.len(),
+                dir.display()
+            );
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_records,
+            max_size,
+            write: Mutex::new(()),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().map_or(0, |state| state.entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn max_records(&self) -> usize {
+        self.max_records
+    }
+
+    /// Total size of all spooled records in bytes
+    pub fn size(&self) -> u64 {
+        self.state.lock().map_or(0, |state| state.size)
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// No more records can be spooled
+    pub fn is_full(&self) -> bool {
+        self.state.lock().map_or(true, |state| {
+            state.entries.len() >= self.max_records || state.size >= self.max_size
+        })
+    }
+
+    /// Appends the record to the spool
+    pub fn push(&self, record: &SpooledRecord) -> Result<(), SpoolError> {
+        let content = serde_json::to_vec(record).map_err(|err| SpoolError::Io(err.to_string()))?;
+        let size = content.len() as u64;
+
+        let _write = self
+            .write
+            .lock()
+            .map_err(|_| SpoolError::Io("Spool lock poisoned".to_string()))?;
+
+        let sequence = {
+            let state = self.lock_state()?;
+            if state.entries.len() >= self.max_records || state.size + size > self.max_size {
+                return Err(SpoolError::Full);
+            }
+            state.next_sequence
+        };
+
+        let tmp_path = self.dir.join(format!("{sequence:020}.tmp"));
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.path(sequence))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        let mut state = self.lock_state()?;
+        state.next_sequence += 1;
+        state.size += size;
+        state.entries.push_back(SpoolEntry { sequence, size });
+
+        Ok(())
+    }
+
+    /// Returns the oldest record and its sequence number without removing it
+    pub fn peek(&self) -> Result<Option<(u64, SpooledRecord)>, SpoolError> {
+        let sequence = match self.lock_state()?.entries.front() {
+            Some(entry) => entry.sequence,
+            None => return Ok(None),
+        };
+
+        let content = fs::read(self.path(sequence))?;
+        match serde_json::from_slice::<SpooledRecord>(&content) {
+            Ok(record) => Ok(Some((sequence, record))),
+            Err(err) => {
+                // Move unreadable record aside to not block all following records
+                let failed_path = self.fail(sequence)?;
+                log::error!(
+                    "Cannot read spooled record, moved to '{}': {err}",
+                    failed_path.display()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Moves a record that can never be sent aside and returns its new path
+    pub fn fail(&self, sequence: u64) -> Result<PathBuf, SpoolError> {
+        let failed_path = self.dir.join(format!("{sequence:020}.failed"));
+        fs::rename(self.path(sequence), &failed_path)?;
+        File::open(&self.dir)?.sync_all()?;
+        self.forget(sequence)?;
+        Ok(failed_path)
+    }
+
+    /// Removes the record with given sequence number after it has been sent
+    pub fn remove(&self, sequence: u64) -> Result<(), SpoolError> {
+        fs::remove_file(self.path(sequence))?;
+        File::open(&self.dir)?.sync_all()?;
+        self.forget(sequence)
+    }
+
+    fn forget(&self, sequence: u64) -> Result<(), SpoolError> {
+        let mut state = self.lock_state()?;
+        if let Some(index) = state
+            .entries
+            .iter()
+            .position(|entry| entry.sequence == sequence)
+            && let Some(entry) = state.entries.remove(index)
+        {
+            state.size -= entry.size;
+        }
+        Ok(())
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, SpoolState>, SpoolError> {
+        self.state
+            .lock()
+            .map_err(|_| SpoolError::Io("Spool lock poisoned".to_string()))
+    }
+
+    fn path(&self, sequence: u64) -> PathBuf {
+        self.dir.join(format!("{sequence:020}.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sender::RequestMethod;
+    use crate::spool::{Spool, SpoolError, SpooledRecord};
+    use crate::test_util::TempDir;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn record(patient_id: &str) -> SpooledRecord {
+        SpooledRecord {
+            request_id: Uuid::new_v4().to_string(),
+            request_method: RequestMethod::Post,
+            key: format!("{{\"pid\":\"{patient_id}\"}}"),
+            payload: format!("{{\"patient\":{{\"id\":\"{patient_id}\"}}}}"),
+            client_identity: Some("test".to_string()),
+        }
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_return_records_in_order() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 10, 1024 * 1024).expect("spool opened");
+
+        let first = record("P1");
+        let second = record("P1");
+        spool.push(&first).expect("record spooled");
+        spool.push(&second).expect("record spooled");
+        assert_eq!(spool.len(), 2);
+
+        let (sequence, spooled) = spool.peek().expect("spool read").expect("record");
+        assert_eq!(spooled, first);
+        spool.remove(sequence).expect("record removed");
+
+        let (sequence, spooled) = spool.peek().expect("spool read").expect("record");
+        assert_eq!(spooled, second);
+        spool.remove(sequence).expect("record removed");
+
+        assert!(spool.is_empty());
+        assert!(spool.peek().expect("spool read").is_none());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_keep_records_after_reopen() {
+        let dir = TempDir::new();
+        let first = record("P1");
+        let second = record("P2");
+
+        {
+            let spool = Spool::open(dir.path(), 10, 1024 * 1024).expect("spool opened");
+            spool.push(&first).expect("record spooled");
+            spool.push(&second).expect("record spooled");
+        }
+
+        // Leftover of incomplete write
+        fs::write(dir.path().join("00000000000000000002.tmp"), "{").expect("file written");
+
+        let spool = Spool::open(dir.path(), 10, 1024 * 1024).expect("spool reopened");
+        assert_eq!(spool.len(), 2);
+        assert!(!dir.path().join("00000000000000000002.tmp").exists());
+
+        let (_, spooled) = spool.peek().expect("spool read").expect("record");
+        assert_eq!(spooled, first);
+
+        let third = record("P3");
+        spool.push(&third).expect("record spooled");
+        assert!(dir.path().join("00000000000000000002.json").exists());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_reject_records_if_record_limit_reached() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 1, 1024 * 1024).expect("spool opened");
+
+        spool.push(&record("P1")).expect("record spooled");
+        assert!(matches!(spool.push(&record("P2")), Err(SpoolError::Full)));
+        assert!(spool.is_full());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_be_full_if_size_limit_reached() {
+        let dir = TempDir::new();
+        let record = record("P1");
+        let size = serde_json::to_vec(&record).expect("serialized").len() as u64;
+        let spool = Spool::open(dir.path(), 10, size).expect("spool opened");
+
+        assert!(!spool.is_full());
+        spool.push(&record).expect("record spooled");
+        assert_eq!(spool.size(), size);
+        assert!(spool.is_full());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_reject_records_if_size_limit_reached() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 10, 64).expect("spool opened");
+
+        assert!(matches!(spool.push(&record("P1")), Err(SpoolError::Full)));
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_move_unreadable_records_aside() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("00000000000000000000.json"), "{").expect("file written");
+
+        let spool = Spool::open(dir.path(), 10, 1024 * 1024).expect("spool opened");
+        assert_eq!(spool.len(), 1);
+        assert!(spool.peek().expect("spool read").is_none());
+        assert!(spool.is_empty());
+        assert!(dir.path().join("00000000000000000000.failed").exists());
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_move_failed_records_aside() {
+        let dir = TempDir::new();
+        let spool = Spool::open(dir.path(), 10, 1024 * 1024).expect("spool opened");
+        let second = record("P2");
+        spool.push(&record("P1")).expect("record spooled");
+        spool.push(&second).expect("record spooled");
+
+        let (sequence, _) = spool.peek().expect("spool read").expect("record");
+        spool.fail(sequence).expect("record moved");
+
+        assert!(dir.path().join("00000000000000000000.failed").exists());
+        let (_, spooled) = spool.peek().expect("spool read").expect("record");
+        assert_eq!(spooled, second);
+    }
+}
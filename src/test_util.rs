@@ -0,0 +1,39 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Directory with a unique name for a test, removed with its content when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    #[allow(clippy::expect_used)]
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("mv64e-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&path).expect("temp dir created");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Writes a file to the directory and returns its path
+    #[allow(clippy::expect_used)]
+    pub fn file(&self, name: &str, content: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, content).expect("file written");
+        path
+    }
+}
+
+impl Default for TempDir {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
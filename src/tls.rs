@@ -0,0 +1,533 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use x509_parser::prelude::{FromDer, X509Certificate, X509Name};
+
+use crate::cli::{CertificateName, Cli};
+
+/// Interval to check certificate files for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum duration of a TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Files used for the HTTPS listener
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Returns configured files if HTTPS is enabled
+    pub fn new(cli: &Cli) -> Option<Self> {
+        match (&cli.tls_cert, &cli.tls_key) {
+            (Some(cert), Some(key)) => Some(Self {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: cli.tls_client_ca.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert.as_path(), self.key.as_path()];
+        if let Some(client_ca) = &self.client_ca {
+            paths.push(client_ca.as_path());
+        }
+        paths
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.paths()
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| format!("Cannot read certificates from '{}': {err}", path.display()))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in '{}'", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Creates server config from certificate files.
+/// Client certificates are required if a client CA is configured.
+pub fn server_config(files: &TlsFiles) -> Result<ServerConfig, String> {
+    let certs = load_certs(&files.cert)?;
+    let key = PrivateKeyDer::from_pem_file(&files.key)
+        .map_err(|err| format!("Cannot read key from '{}': {err}", files.key.display()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+
+    let builder = match &files.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca)? {
+                roots.add(cert).map_err(|err| {
+                    format!("Invalid CA certificate in '{}': {err}", client_ca.display())
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|err| err.to_string())?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Invalid certificate or key: {err}"))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Server config that is reloaded if certificate files change
+struct ReloadableConfig {
+    files: TlsFiles,
+    current: RwLock<Arc<ServerConfig>>,
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadableConfig {
+    fn new(files: TlsFiles) -> Result<Self, String> {
+        let modified = files.modified();
+        let config = server_config(&files)?;
+        Ok(Self {
+            files,
+            current: RwLock::new(Arc::new(config)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    fn current(&self) -> Option<Arc<ServerConfig>> {
+        self.current.read().ok().map(|config| Arc::clone(&config))
+    }
+
+    /// Returns true if changed files have been loaded.
+    /// Invalid files are logged and the current config is kept.
+    fn reload_if_changed(&self) -> bool {
+        let modified = self.files.modified();
+        let Ok(mut last_modified) = self.modified.lock() else {
+            return false;
+        };
+        if *last_modified == modified {
+            return false;
+        }
+
+        match server_config(&self.files) {
+            Ok(config) => {
+                if let Ok(mut current) = self.current.write() {
+                    *current = Arc::new(config);
+                }
+                *last_modified = modified;
+                log::info!(
+                    "Reloaded TLS certificate from '{}'",
+                    self.files.cert.display()
+                );
+                true
+            }
+            Err(err) => {
+                log::error!("Cannot reload TLS certificate, keeping current certificate: {err}");
+                false
+            }
+        }
+    }
+}
+
+async fn reload_periodically(config: Weak<ReloadableConfig>) {
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        match config.upgrade() {
+            Some(config) => {
+                tokio::task::spawn_blocking(move || config.reload_if_changed())
+                    .await
+                    .ok();
+            }
+            None => return,
+        }
+    }
+}
+
+async fn accept_connections(
+    tcp_listener: TcpListener,
+    config: Arc<ReloadableConfig>,
+    connections: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    while !connections.is_closed() {
+        let (stream, addr) = match tcp_listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::error!("Cannot accept connection: {err}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Some(server_config) = config.current() else {
+            continue;
+        };
+        let connections = connections.clone();
+
+        // Handshakes run concurrently, so slow clients do not block others
+        tokio::spawn(async move {
+            match tokio::time::timeout(
+                HANDSHAKE_TIMEOUT,
+                TlsAcceptor::from(server_config).accept(stream),
+            )
+            .await
+            {
+                Ok(Ok(tls_stream)) => {
+                    let _ = connections.send((tls_stream, addr)).await;
+                }
+                Ok(Err(err)) => log::warn!("TLS handshake with '{addr}' failed: {err}"),
+                Err(_) => log::warn!("TLS handshake with '{addr}' timed out"),
+            }
+        });
+    }
+}
+
+/// Listener accepting TLS connections only
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(tcp_listener: TcpListener, files: TlsFiles) -> Result<Self, String> {
+        let local_addr = tcp_listener.local_addr().map_err(|err| err.to_string())?;
+        let config = Arc::new(ReloadableConfig::new(files)?);
+        let (sender, connections) = mpsc::channel(64);
+
+        tokio::spawn(reload_periodically(Arc::downgrade(&config)));
+        tokio::spawn(accept_connections(tcp_listener, config, sender));
+
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Names of a verified client certificate
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientCertificate {
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    pub uris: Vec<String>,
+}
+
+impl ClientCertificate {
+    fn parse(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, x509) = X509Certificate::from_der(cert).ok()?;
+        let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+        Some(Self {
+            common_name: common_name(x509.subject()),
+            dns_names: cert.valid_dns_names().map(str::to_string).collect(),
+            uris: cert.valid_uri_names().map(str::to_string).collect(),
+        })
+    }
+
+    pub fn matches(&self, name: &CertificateName) -> bool {
+        match name {
+            CertificateName::CommonName(cn) => self.common_name.as_ref() == Some(cn),
+            CertificateName::DnsName(dns) => self.dns_names.contains(dns),
+            CertificateName::Uri(uri) => self.uris.contains(uri),
+        }
+    }
+}
+
+/// Returns the common name of the subject, `None` if missing or ambiguous
+fn common_name(subject: &X509Name<'_>) -> Option<String> {
+    let mut common_names = subject.iter_common_name();
+    let common_name = common_names.next()?;
+    if common_names.next().is_some() {
+        return None;
+    }
+    match common_name.as_str() {
+        Ok(value) => Some(value.to_string()),
+        Err(_) => common_name
+            .attr_value()
+            .as_bmpstring()
+            .ok()
+            .map(|value| value.string()),
+    }
+}
+
+/// Information about the connection of a request
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ConnectionInfo {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self {
+            remote_addr: *stream.remote_addr(),
+            client_certificate: None,
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ConnectionInfo {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        let (_, connection) = stream.io().get_ref();
+        Self {
+            remote_addr: *stream.remote_addr(),
+            client_certificate: connection
+                .peer_certificates()
+                .and_then(<[_]>::first)
+                .and_then(ClientCertificate::parse),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::TempDir;
+    use crate::tls::{
+        ClientCertificate, ConnectionInfo, ReloadableConfig, TlsFiles, TlsListener, server_config,
+    };
+    use axum::Router;
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use rstest::rstest;
+    use rustls::pki_types::CertificateDer;
+    use rustls::pki_types::pem::PemObject;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use ureq::tls::{Certificate, ClientCert, PrivateKey, RootCerts, TlsConfig};
+
+    const TEST_FILES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-files/tls");
+
+    fn test_file(name: &str) -> PathBuf {
+        PathBuf::from(TEST_FILES).join(name)
+    }
+
+    fn tls_files(client_ca: bool) -> TlsFiles {
+        TlsFiles {
+            cert: test_file("server.pem"),
+            key: test_file("server.key"),
+            client_ca: if client_ca {
+                Some(test_file("ca.pem"))
+            } else {
+                None
+            },
+        }
+    }
+
+    #[allow(clippy::expect_used)]
+    async fn serve(files: TlsFiles) -> u16 {
+        let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("listener bound");
+        let listener = TlsListener::new(tcp_listener, files).expect("TLS listener created");
+        let port = listener.local_addr.port();
+        tokio::spawn(async move {
+            // Responds with the common name of the client certificate if present
+            let router = Router::new().route(
+                "/",
+                get(
+                    |ConnectInfo(connection_info): ConnectInfo<ConnectionInfo>| async move {
+                        connection_info
+                            .client_certificate
+                            .and_then(|client_certificate| client_certificate.common_name)
+                            .unwrap_or("ok".to_string())
+                    },
+                ),
+            );
+            let _ = axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<ConnectionInfo>(),
+            )
+            .await;
+        });
+        port
+    }
+
+    #[allow(clippy::expect_used)]
+    async fn get_with_client(port: u16, client_cert: bool) -> Result<String, ureq::Error> {
+        tokio::task::spawn_blocking(move || {
+            let ca = fs::read(test_file("ca.pem")).expect("CA read");
+            let mut tls_config = TlsConfig::builder().root_certs(RootCerts::new_with_certs(&[
+                Certificate::from_pem(&ca).expect("CA parsed"),
+            ]));
+            if client_cert {
+                let cert = fs::read(test_file("client.pem")).expect("certificate read");
+                let key = fs::read(test_file("client.key")).expect("key read");
+                tls_config = tls_config.client_cert(Some(ClientCert::new_with_certs(
+                    &[Certificate::from_pem(&cert).expect("certificate parsed")],
+                    PrivateKey::from_pem(&key).expect("key parsed"),
+                )));
+            }
+            ureq::Agent::config_builder()
+                .tls_config(tls_config.build())
+                .timeout_global(Some(Duration::from_secs(5)))
+                .build()
+                .new_agent()
+                .get(format!("https://localhost:{port}/"))
+                .call()
+                .and_then(|mut response| response.body_mut().read_to_string())
+        })
+        .await
+        .expect("request completed")
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_parse_client_certificate_names() {
+        let cert =
+            CertificateDer::from_pem_file(test_file("client.pem")).expect("certificate read");
+
+        assert_eq!(
+            ClientCertificate::parse(&cert),
+            Some(ClientCertificate {
+                common_name: Some("mtb-documentation".to_string()),
+                dns_names: vec!["mtb-documentation.example.org".to_string()],
+                uris: vec![],
+            })
+        );
+    }
+
+    #[rstest]
+    #[case::multi_valued_rdn("client-multivalued-rdn.pem", Some("mtb-pathology"))]
+    #[case::bmp_string("client-bmpstring.pem", Some("mtb-łódź"))]
+    #[case::ambiguous("client-ambiguous-cn.pem", None)]
+    fn should_parse_client_certificate_common_name(
+        #[case] file: &str,
+        #[case] common_name: Option<&str>,
+    ) {
+        let cert = CertificateDer::from_pem_file(test_file(file))
+            .ok()
+            .and_then(|cert| ClientCertificate::parse(&cert));
+
+        assert_eq!(
+            cert.map(|cert| cert.common_name),
+            Some(common_name.map(str::to_string))
+        );
+    }
+
+    #[test]
+    fn should_create_server_config() {
+        assert!(server_config(&tls_files(false)).is_ok());
+        assert!(server_config(&tls_files(true)).is_ok());
+    }
+
+    #[test]
+    fn should_reject_missing_files() {
+        let mut files = tls_files(false);
+        files.cert = test_file("missing.pem");
+
+        assert!(server_config(&files).is_err());
+    }
+
+    #[test]
+    fn should_reject_key_not_matching_certificate() {
+        let mut files = tls_files(false);
+        files.key = test_file("client.key");
+
+        assert!(server_config(&files).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_serve_https() {
+        let port = serve(tls_files(false)).await;
+
+        assert_eq!(
+            get_with_client(port, false).await.ok(),
+            Some("ok".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_require_client_certificate() {
+        let port = serve(tls_files(true)).await;
+
+        assert!(get_with_client(port, false).await.is_err());
+        assert_eq!(
+            get_with_client(port, true).await.ok(),
+            Some("mtb-documentation".to_string())
+        );
+    }
+
+    #[test]
+    #[allow(clippy::expect_used)]
+    fn should_reload_changed_certificate() {
+        let temp_dir = TempDir::new();
+        let dir = temp_dir.path();
+        fs::copy(test_file("server.pem"), dir.join("server.pem")).expect("file copied");
+        fs::copy(test_file("server.key"), dir.join("server.key")).expect("file copied");
+
+        let config = ReloadableConfig::new(TlsFiles {
+            cert: dir.join("server.pem"),
+            key: dir.join("server.key"),
+            client_ca: None,
+        })
+        .expect("config loaded");
+        let initial = config.current().expect("current config");
+
+        assert!(!config.reload_if_changed());
+
+        // Invalid files are not used
+        fs::write(dir.join("server.pem"), "invalid").expect("file written");
+        assert!(!config.reload_if_changed());
+        assert!(Arc::ptr_eq(
+            &initial,
+            &config.current().expect("current config")
+        ));
+
+        fs::copy(test_file("server.pem"), dir.join("server.pem")).expect("file copied");
+        fs::File::options()
+            .write(true)
+            .open(dir.join("server.pem"))
+            .and_then(|file| file.set_modified(SystemTime::now() + Duration::from_mins(1)))
+            .expect("modification time set");
+        assert!(config.reload_if_changed());
+        assert!(!Arc::ptr_eq(
+            &initial,
+            &config.current().expect("current config")
+        ));
+    }
+}